@@ -0,0 +1,3 @@
+pub mod scan_schedule;
+#[cfg(target_os = "linux")]
+pub mod watch_set;
@@ -0,0 +1,236 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::datastructures::merkle_tree::MerkleTree;
+
+/// Subtrees modified within `modified_within` are scanned every `interval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanTier {
+    pub modified_within: Duration,
+    pub interval: Duration,
+}
+
+/// Scan intervals depending on how recently a subtree changed.
+#[derive(Debug, Clone)]
+pub struct ScanIntervals {
+    tiers: Vec<ScanTier>,
+    /// used for subtrees older than every tier
+    fallback: Duration,
+}
+impl ScanIntervals {
+    pub fn new(mut tiers: Vec<ScanTier>, fallback: Duration) -> Self {
+        tiers.sort_by_key(|tier| tier.modified_within);
+        Self { tiers, fallback }
+    }
+
+    /// Index of the tier for a subtree last modified `age` ago. Lower is more frequent.
+    fn tier(&self, age: Duration) -> usize {
+        self.tiers
+            .iter()
+            .position(|tier| age <= tier.modified_within)
+            .unwrap_or(self.tiers.len())
+    }
+
+    fn interval(&self, tier: usize) -> Duration {
+        self.tiers
+            .get(tier)
+            .map_or(self.fallback, |tier| tier.interval)
+    }
+}
+impl Default for ScanIntervals {
+    /// Between 2 minutes for subtrees changed within the last hour and 5 hours for subtrees unchanged for a week.
+    fn default() -> Self {
+        const HOUR: Duration = Duration::from_secs(60 * 60);
+        Self::new(
+            vec![
+                ScanTier {
+                    modified_within: HOUR,
+                    interval: Duration::from_secs(2 * 60),
+                },
+                ScanTier {
+                    modified_within: 24 * HOUR,
+                    interval: Duration::from_secs(15 * 60),
+                },
+                ScanTier {
+                    modified_within: 7 * 24 * HOUR,
+                    interval: HOUR,
+                },
+            ],
+            5 * HOUR,
+        )
+    }
+}
+
+/// A subtree that is scanned on its own schedule.
+#[derive(Debug, Clone)]
+pub struct ScheduledScan {
+    pub interval: Duration,
+    pub last_scan: Instant,
+    /// nested subtrees that have their own schedule and are skipped when scanning this one
+    pub skip: Vec<PathBuf>,
+}
+impl ScheduledScan {
+    pub fn next_scan(&self) -> Instant {
+        self.last_scan + self.interval
+    }
+}
+
+/// Decides when which part of the tree is scanned.
+///
+/// The tree is split into subtrees whose directories were all modified in the same tier, so directories
+/// that did not change for a long time are not scanned just because one of their siblings did.
+pub struct ScanScheduler {
+    intervals: ScanIntervals,
+    schedule: BTreeMap<PathBuf, ScheduledScan>,
+}
+impl ScanScheduler {
+    pub fn new(intervals: ScanIntervals) -> Self {
+        Self {
+            intervals,
+            schedule: BTreeMap::new(),
+        }
+    }
+
+    /// Splits `tree` into subtrees by the last modification of its directories.
+    /// Subtrees that were already scheduled keep their last scan, new ones count as just scanned.
+    pub fn update<K>(&mut self, tree: &MerkleTree<K>)
+    where
+        K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+    {
+        let now_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let tiers = tree
+            .directories()
+            .map(|(path, last_modified)| {
+                let age = Duration::from_secs(now_secs.saturating_sub(last_modified));
+                (path, self.intervals.tier(age))
+            })
+            .collect::<HashMap<_, _>>();
+
+        // a directory starts a new subtree if its tier differs from its parent's
+        let roots = tiers
+            .iter()
+            .filter(|(path, tier)| {
+                path.parent()
+                    .and_then(|parent| tiers.get(parent))
+                    .is_none_or(|parent_tier| parent_tier != *tier)
+            })
+            .map(|(path, tier)| (*path, *tier))
+            .collect::<HashMap<_, _>>();
+
+        let now = Instant::now();
+        let mut schedule = roots
+            .iter()
+            .map(|(path, tier)| {
+                let last_scan = self.schedule.get(*path).map_or(now, |scan| scan.last_scan);
+                let scan = ScheduledScan {
+                    interval: self.intervals.interval(*tier),
+                    last_scan,
+                    skip: Vec::new(),
+                };
+                (path.to_path_buf(), scan)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for path in roots.keys() {
+            let parent_root = path
+                .ancestors()
+                .skip(1)
+                .find(|ancestor| roots.contains_key(ancestor));
+            if let Some(parent_root) = parent_root {
+                let parent = schedule.get_mut(parent_root).unwrap();
+                parent.skip.push(path.to_path_buf());
+            }
+        }
+        self.schedule = schedule;
+    }
+
+    /// Every scheduled subtree by its root, whether it is due or not.
+    pub fn schedule(&self) -> impl Iterator<Item = (&Path, &ScheduledScan)> {
+        self.schedule
+            .iter()
+            .map(|(path, scan)| (path.as_path(), scan))
+    }
+
+    /// The subtrees whose next scan is at or before `now`.
+    pub fn due(&self, now: Instant) -> Vec<(&Path, &ScheduledScan)> {
+        self.schedule()
+            .filter(|(_, scan)| scan.next_scan() <= now)
+            .collect()
+    }
+
+    pub fn mark_scanned(&mut self, root: &Path, now: Instant) {
+        if let Some(scan) = self.schedule.get_mut(root) {
+            scan.last_scan = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{ScanIntervals, ScanScheduler};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn tree_is_split_into_tiers() {
+        const HOUR: u64 = 60 * 60;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let dir = TempDir::new();
+        dir.write("hot/file", "a");
+        dir.write("hot/old/file", "b");
+        dir.write("warm/file", "c");
+        dir.write("cold/file", "d");
+        dir.set_modified("hot/old/file", 1000);
+        dir.set_modified("warm/file", now - 2 * HOUR);
+        dir.set_modified("cold/file", now - 30 * 24 * HOUR);
+
+        let mut scheduler = ScanScheduler::new(ScanIntervals::default());
+        let before = Instant::now();
+        scheduler.update(&dir.scan());
+
+        let schedule = scheduler
+            .schedule()
+            .map(|(path, scan)| {
+                let mut skip = scan.skip.clone();
+                skip.sort();
+                (path.to_path_buf(), scan.interval, skip)
+            })
+            .collect::<Vec<_>>();
+        let path = |relative: &str| dir.path().join(relative);
+        let minutes = |minutes: u64| Duration::from_secs(minutes * 60);
+        let expected: Vec<(PathBuf, Duration, Vec<PathBuf>)> = vec![
+            (
+                dir.path().to_path_buf(),
+                minutes(2),
+                vec![path("cold"), path("hot/old"), path("warm")],
+            ),
+            (path("cold"), minutes(5 * 60), vec![]),
+            (path("hot/old"), minutes(5 * 60), vec![]),
+            (path("warm"), minutes(15), vec![]),
+        ];
+        assert_eq!(schedule, expected);
+
+        // new subtrees count as just scanned, so none is due yet
+        assert!(scheduler.due(before).is_empty());
+        let warm = scheduler
+            .schedule()
+            .find(|(subtree, _)| *subtree == path("warm"))
+            .unwrap()
+            .1;
+        assert!(warm.next_scan() >= before + minutes(15));
+        assert_eq!(scheduler.due(warm.next_scan()).len(), 2);
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    datastructures::merkle_tree::MerkleTree, error::SyncronError, filesystem::watcher::Watcher,
+};
+
+const MAX_USER_WATCHES: &str = "/proc/sys/fs/inotify/max_user_watches";
+
+/// Decides which directories are watched. Since the number of inotify watches is limited,
+/// only the directories with the most recent changes are watched.
+pub struct WatchSetScheduler {
+    budget: usize,
+}
+
+/// What [WatchSetScheduler::update] changed.
+#[derive(Debug, Default)]
+pub struct WatchSetUpdate {
+    pub watched: Vec<PathBuf>,
+    pub unwatched: Vec<PathBuf>,
+    pub errors: Vec<SyncronError>,
+}
+
+impl WatchSetScheduler {
+    /// Creates a scheduler that watches at most `budget` directories, limited further by the kernel's `max_user_watches`.
+    pub fn new(budget: usize) -> Self {
+        let max_user_watches = fs::read_to_string(MAX_USER_WATCHES)
+            .ok()
+            .and_then(|max| max.trim().parse().ok())
+            .unwrap_or(usize::MAX);
+        Self {
+            budget: budget.min(max_user_watches),
+        }
+    }
+
+    /// Ranks the directories of `tree` by their last modification and moves the watches of `watcher`
+    /// to the `budget` most recently modified ones.
+    ///
+    /// If the kernel runs out of watches first, only the directories watched so far are kept. Later
+    /// updates try the full budget again, since other programs may have released their watches by then.
+    pub fn update<K>(&self, tree: &MerkleTree<K>, watcher: &mut Watcher) -> WatchSetUpdate
+    where
+        K: Eq + Ord + Clone + std::hash::Hash + AsRef<[u8]>,
+    {
+        let mut update = WatchSetUpdate::default();
+        let hottest = self.hottest(tree);
+
+        let stale = watcher
+            .watched()
+            .filter(|dir| !hottest.contains(dir))
+            .map(Path::to_owned)
+            .collect::<Vec<_>>();
+        for dir in stale {
+            watcher.unwatch(&dir);
+            update.unwatched.push(dir);
+        }
+
+        for dir in hottest {
+            if watcher.is_watched(dir) {
+                continue;
+            }
+            match watcher.watch(dir) {
+                Ok(()) => update.watched.push(dir.to_owned()),
+                // other programs use watches as well, so the limit might be reached before our budget is
+                Err(SyncronError::Io { source, .. })
+                    if source.kind() == io::ErrorKind::StorageFull =>
+                {
+                    break;
+                }
+                Err(err) => update.errors.push(err),
+            }
+        }
+        update
+    }
+
+    /// The `budget` most recently modified directories.
+    fn hottest<'a, K>(&self, tree: &'a MerkleTree<K>) -> HashSet<&'a Path>
+    where
+        K: Eq + Ord + Clone + std::hash::Hash + AsRef<[u8]>,
+    {
+        let mut directories = tree.directories().collect::<Vec<_>>();
+        // most recent first, ties are broken by path so the selection is stable
+        directories.sort_unstable_by(|(path_a, modified_a), (path_b, modified_b)| {
+            modified_b.cmp(modified_a).then_with(|| path_a.cmp(path_b))
+        });
+        directories
+            .into_iter()
+            .take(self.budget)
+            .map(|(path, _)| path)
+            .collect()
+    }
+}
@@ -0,0 +1,117 @@
+use std::{borrow::Borrow, fmt};
+
+use crate::filesystem::data::MerkleEntry;
+
+/// A single difference between two states of a tree.
+///
+/// Paths are the segments relative to the tree root. `E` is the entry type attached to the change,
+/// which is `&MerkleEntry` when diffing two existing trees and `MerkleEntry` when the change is owned.
+#[derive(Debug, Clone)]
+pub enum Change<K, E = MerkleEntry> {
+    Added {
+        path: Vec<K>,
+        new: E,
+    },
+    Modified {
+        path: Vec<K>,
+        old: E,
+        new: E,
+    },
+    Deleted {
+        path: Vec<K>,
+        old: E,
+    },
+    Moved {
+        from: Vec<K>,
+        to: Vec<K>,
+        old: E,
+        new: E,
+    },
+    /// An entry was added with the same content as an unchanged entry at `from`, so it can be copied locally.
+    Copied {
+        from: Vec<K>,
+        to: Vec<K>,
+        new: E,
+    },
+    /// A file was replaced by a directory or vice versa.
+    TypeChanged {
+        path: Vec<K>,
+        old: E,
+        new: E,
+    },
+}
+impl<K, E: Borrow<MerkleEntry>> Change<K, E> {
+    /// The path the change results in (for moves and copies the destination).
+    pub fn path(&self) -> &[K] {
+        match self {
+            Self::Added { path, .. }
+            | Self::Modified { path, .. }
+            | Self::Deleted { path, .. }
+            | Self::TypeChanged { path, .. } => path,
+            Self::Moved { to, .. } | Self::Copied { to, .. } => to,
+        }
+    }
+
+    /// The paths the change touches, i.e. both paths of a move. A copy does not touch its source.
+    pub fn paths(&self) -> Vec<&[K]> {
+        match self {
+            Self::Moved { from, to, .. } => vec![from, to],
+            _ => vec![self.path()],
+        }
+    }
+
+    /// The entry before the change, `None` if there was nothing.
+    pub fn old_entry(&self) -> Option<&MerkleEntry> {
+        match self {
+            Self::Added { .. } | Self::Copied { .. } => None,
+            Self::Modified { old, .. }
+            | Self::Deleted { old, .. }
+            | Self::Moved { old, .. }
+            | Self::TypeChanged { old, .. } => Some(old.borrow()),
+        }
+    }
+
+    /// The entry after the change, `None` if there is nothing.
+    pub fn new_entry(&self) -> Option<&MerkleEntry> {
+        match self {
+            Self::Deleted { .. } => None,
+            Self::Added { new, .. }
+            | Self::Copied { new, .. }
+            | Self::Modified { new, .. }
+            | Self::Moved { new, .. }
+            | Self::TypeChanged { new, .. } => Some(new.borrow()),
+        }
+    }
+}
+
+/// One line per change, e.g. `moved directory a -> b` or `replaced file c with a directory`.
+impl<K: AsRef<[u8]>, E: Borrow<MerkleEntry>> fmt::Display for Change<K, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = |entry: Option<&MerkleEntry>| match entry {
+            Some(MerkleEntry::Directory(_)) => "directory",
+            _ => "file",
+        };
+        let (old, new) = (kind(self.old_entry()), kind(self.new_entry()));
+        match self {
+            Self::Added { path, .. } => write!(f, "added {new} {}", display(path)),
+            Self::Modified { path, .. } => write!(f, "modified {new} {}", display(path)),
+            Self::Deleted { path, .. } => write!(f, "deleted {old} {}", display(path)),
+            Self::Moved { from, to, .. } => {
+                write!(f, "moved {new} {} -> {}", display(from), display(to))
+            }
+            Self::Copied { from, to, .. } => {
+                write!(f, "copied {new} {} -> {}", display(from), display(to))
+            }
+            Self::TypeChanged { path, .. } => {
+                write!(f, "replaced {old} {} with a {new}", display(path))
+            }
+        }
+    }
+}
+
+fn display<K: AsRef<[u8]>>(path: &[K]) -> String {
+    path.iter()
+        .map(|segment| String::from_utf8_lossy(segment.as_ref()))
+        .collect::<Vec<_>>()
+        .join("/")
+}
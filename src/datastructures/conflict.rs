@@ -0,0 +1,326 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeSet,
+    fs,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use super::{change::Change, merge::Conflict, merkle_tree::MerkleTree, version_vector::ReplicaId};
+use crate::{error::SyncronError, filesystem::data::MerkleEntry};
+
+/// File in the sync root that chooses the [ConflictStrategy] by path, see [ConflictResolver::load].
+/// It is synced like any other file, so every replica resolves conflicts the same way.
+pub const CONFLICTS_FILE: &str = ".syncconflicts";
+
+/// How a path both replicas changed concurrently is resolved.
+///
+/// Except for preferring a side, every strategy chooses the same winner on both replicas,
+/// so they agree without talking to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// The newest version wins, the other one is kept as a conflict copy next to it.
+    KeepBoth,
+    PreferLocal,
+    PreferRemote,
+    PreferNewest,
+    /// The larger file wins, e.g. for logs that are only appended to.
+    PreferLarger,
+}
+impl FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keep-both" => Ok(Self::KeepBoth),
+            "prefer-local" => Ok(Self::PreferLocal),
+            "prefer-remote" => Ok(Self::PreferRemote),
+            "prefer-newest" => Ok(Self::PreferNewest),
+            "prefer-larger" => Ok(Self::PreferLarger),
+            _ => Err(format!("unknown conflict strategy {name:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+/// What to do with one path of a [Conflict].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution<K> {
+    pub path: Vec<K>,
+    pub winner: Side,
+    /// where the version of the loser is kept, if it is kept
+    pub copy: Option<Vec<K>>,
+}
+
+/// Chooses a [ConflictStrategy] by path and resolves conflicts with it.
+pub struct ConflictResolver {
+    /// patterns with their strategy, the first matching pattern wins
+    rules: Vec<(Gitignore, ConflictStrategy)>,
+    default: ConflictStrategy,
+}
+impl ConflictResolver {
+    pub fn new(default: ConflictStrategy) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    /// A resolver with the rules of the [CONFLICTS_FILE] in `root`, which uses `default` for paths no rule matches.
+    ///
+    /// Every line of the file is a pattern with the syntax of a .gitignore line, followed by the strategy
+    /// for the paths it matches, e.g. `*.log prefer-larger`. Empty lines and lines starting with `#` are skipped.
+    /// Invalid lines are reported, the valid rules of the file are still used.
+    pub fn load(root: &Path, default: ConflictStrategy) -> (Self, Vec<SyncronError>) {
+        let mut resolver = Self::new(default);
+        let path = root.join(CONFLICTS_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return (resolver, Vec::new()),
+            Err(err) => return (resolver, vec![SyncronError::from_io(path, err)]),
+        };
+
+        let mut errors = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = match line.rsplit_once(char::is_whitespace) {
+                Some((pattern, strategy)) => strategy.parse().and_then(|strategy| {
+                    resolver
+                        .add_rule(pattern.trim_end(), strategy)
+                        .map_err(|err| err.to_string())
+                }),
+                None => Err("expected a pattern and a conflict strategy".to_string()),
+            };
+            if let Err(message) = rule {
+                errors.push(SyncronError::Config {
+                    path: path.clone(),
+                    line: number + 1,
+                    message,
+                });
+            }
+        }
+        (resolver, errors)
+    }
+
+    /// Uses `strategy` for paths matching `pattern`, which has the syntax of a .gitignore line.
+    /// Rules are checked in the order they were added.
+    pub fn add_rule(
+        &mut self,
+        pattern: &str,
+        strategy: ConflictStrategy,
+    ) -> Result<(), ignore::Error> {
+        let mut builder = GitignoreBuilder::new("");
+        builder.add_line(None, pattern)?;
+        self.rules.push((builder.build()?, strategy));
+        Ok(())
+    }
+
+    /// The strategy for `path`, relative to the sync root.
+    pub fn strategy(&self, path: &Path, is_dir: bool) -> ConflictStrategy {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| {
+                pattern
+                    .matched_path_or_any_parents(path, is_dir)
+                    .is_ignore()
+            })
+            .map_or(self.default, |(_, strategy)| *strategy)
+    }
+
+    /// Decides for every path of `conflict` which side wins, with `local` and `remote` being the trees it was found in.
+    /// Parents are resolved before their children.
+    ///
+    /// Where only one side still has an entry, that side wins unless a side is preferred, so no data is lost.
+    pub fn resolve<K>(
+        &self,
+        local: &MerkleTree<K>,
+        remote: &MerkleTree<K>,
+        conflict: &Conflict<K>,
+    ) -> Vec<Resolution<K>>
+    where
+        K: Eq + Ord + Clone + Hash + AsRef<[u8]> + for<'a> From<&'a str>,
+    {
+        let paths = conflict
+            .local
+            .iter()
+            .chain(&conflict.remote)
+            .flat_map(Change::paths)
+            .collect::<BTreeSet<_>>();
+
+        let mut resolutions = Vec::new();
+        for path in paths {
+            let local_entry = local.try_get(path);
+            let remote_entry = remote.try_get(path);
+            let is_dir = [local_entry, remote_entry]
+                .into_iter()
+                .flatten()
+                .any(|entry| matches!(entry, MerkleEntry::Directory(_)));
+            let relative = path
+                .iter()
+                .map(|segment| String::from_utf8_lossy(segment.as_ref()).into_owned())
+                .collect::<PathBuf>();
+            let strategy = self.strategy(&relative, is_dir);
+
+            let resolution = match (local_entry, remote_entry) {
+                (Some(MerkleEntry::File(_)), Some(MerkleEntry::File(_))) => {
+                    let local_version = Version::of(local, path);
+                    let remote_version = Version::of(remote, path);
+                    let winner = match strategy {
+                        ConflictStrategy::PreferLocal => Side::Local,
+                        ConflictStrategy::PreferRemote => Side::Remote,
+                        ConflictStrategy::KeepBoth | ConflictStrategy::PreferNewest => {
+                            local_version.newest(&remote_version)
+                        }
+                        ConflictStrategy::PreferLarger => local_version.larger(&remote_version),
+                    };
+                    let copy = (strategy == ConflictStrategy::KeepBoth).then(|| {
+                        let loser = match winner {
+                            Side::Local => &remote_version,
+                            Side::Remote => &local_version,
+                        };
+                        conflict_copy_path(path, loser.replica, loser.last_modified)
+                    });
+                    Resolution {
+                        path: path.to_vec(),
+                        winner,
+                        copy,
+                    }
+                }
+                (local_entry, remote_entry) => {
+                    let winner = match (strategy, local_entry, remote_entry) {
+                        (ConflictStrategy::PreferLocal, _, _) => Side::Local,
+                        (ConflictStrategy::PreferRemote, _, _) => Side::Remote,
+                        (_, Some(_), None) => Side::Local,
+                        (_, None, Some(_)) => Side::Remote,
+                        // a file and a directory, or gone on both sides
+                        _ => Version::of(local, path).newest(&Version::of(remote, path)),
+                    };
+                    Resolution {
+                        path: path.to_vec(),
+                        winner,
+                        copy: None,
+                    }
+                }
+            };
+            resolutions.push(resolution);
+        }
+        resolutions
+    }
+}
+
+/// What the strategies compare of both sides' version of a path.
+struct Version {
+    replica: ReplicaId,
+    last_modified: u64,
+    size: u64,
+}
+impl Version {
+    fn of<K: Eq + Ord + Clone + Hash + AsRef<[u8]>>(tree: &MerkleTree<K>, path: &[K]) -> Self {
+        Self {
+            replica: tree.replica(),
+            last_modified: tree
+                .try_get(path)
+                .map_or(0, |_| tree.get_last_modified(path)),
+            size: tree.try_get(path).map_or(0, MerkleEntry::get_size),
+        }
+    }
+
+    fn newest(&self, remote: &Self) -> Side {
+        self.winner(remote, self.last_modified.cmp(&remote.last_modified))
+    }
+
+    fn larger(&self, remote: &Self) -> Side {
+        self.winner(remote, self.size.cmp(&remote.size))
+    }
+
+    /// Ties are broken by replica id, so both replicas choose the same winner.
+    fn winner(&self, remote: &Self, ordering: Ordering) -> Side {
+        match ordering.then_with(|| self.replica.cmp(&remote.replica)) {
+            Ordering::Less => Side::Remote,
+            Ordering::Equal | Ordering::Greater => Side::Local,
+        }
+    }
+}
+
+/// `name (conflict from <replica> <date>).ext` next to `path`.
+fn conflict_copy_path<K>(path: &[K], replica: ReplicaId, last_modified: u64) -> Vec<K>
+where
+    K: Clone + AsRef<[u8]> + for<'a> From<&'a str>,
+{
+    let (name, parent) = path.split_last().expect("the root can't conflict");
+    let name = String::from_utf8_lossy(name.as_ref());
+    // a leading dot starts a hidden name, not an extension
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name.as_ref(), ""),
+    };
+    let (year, month, day) = civil_date(last_modified);
+    let copy = format!("{stem} (conflict from {replica} {year:04}-{month:02}-{day:02}){extension}");
+
+    let mut copy_path = parent.to_vec();
+    copy_path.push(K::from(copy.as_str()));
+    copy_path
+}
+
+/// The UTC date of a unix timestamp in seconds.
+fn civil_date(secs: u64) -> (i64, u32, u32) {
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / (24 * 60 * 60)) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{ConflictResolver, ConflictStrategy, CONFLICTS_FILE};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn conflicts_file_chooses_strategy_by_path() {
+        let dir = TempDir::new();
+        dir.write(
+            CONFLICTS_FILE,
+            "# comment\n*.log prefer-larger\nbuild/ prefer-newest\n*.txt prefer-everything\nnostrategy\n",
+        );
+        let (resolver, errors) = ConflictResolver::load(dir.path(), ConflictStrategy::KeepBoth);
+
+        assert_eq!(errors.len(), 2);
+        let strategy = |path: &str, is_dir| resolver.strategy(Path::new(path), is_dir);
+        assert_eq!(
+            strategy("logs/app.log", false),
+            ConflictStrategy::PreferLarger
+        );
+        assert_eq!(
+            strategy("build/out.bin", false),
+            ConflictStrategy::PreferNewest
+        );
+        assert_eq!(strategy("build", true), ConflictStrategy::PreferNewest);
+        assert_eq!(strategy("notes.txt", false), ConflictStrategy::KeepBoth);
+    }
+}
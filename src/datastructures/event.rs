@@ -0,0 +1,33 @@
+use blake3::Hash as BHash;
+
+/// A change of a [MerkleTree](super::merkle_tree::MerkleTree) that still has to be done on disk,
+/// as published to the subscriber of the tree.
+///
+/// Paths are the segments relative to the tree root. Events are published in the order they have to be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeEvent<K> {
+    /// A file with the content of `hash` was created where there was nothing.
+    CreateFile {
+        path: Vec<K>,
+        hash: BHash,
+    },
+    /// The content of a file was replaced with the content of `hash`.
+    UpdateFile {
+        path: Vec<K>,
+        hash: BHash,
+    },
+    DeleteFile(Vec<K>),
+    CreateDir(Vec<K>),
+    /// A directory was deleted with everything below it.
+    DeleteDir(Vec<K>),
+    /// An entry and everything below it was moved to where there is nothing.
+    Move {
+        from: Vec<K>,
+        to: Vec<K>,
+    },
+    /// An entry and everything below it was copied to where there is nothing.
+    Copy {
+        from: Vec<K>,
+        to: Vec<K>,
+    },
+}
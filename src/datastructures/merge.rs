@@ -0,0 +1,292 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    hash::Hash,
+};
+
+use blake3::Hash as BHash;
+
+use super::{change::Change, merkle_tree::MerkleTree, version_vector::Causality};
+use crate::filesystem::data::MerkleEntry;
+
+/// What has to happen to bring a local and a remote tree together, see [MerkleTree::merge_plan].
+#[derive(Debug)]
+pub struct MergePlan<'a, K> {
+    /// local changes that have to be applied to the remote
+    pub push: Vec<Change<K, &'a MerkleEntry>>,
+    /// remote changes that have to be applied locally
+    pub pull: Vec<Change<K, &'a MerkleEntry>>,
+    pub conflicts: Vec<Conflict<'a, K>>,
+}
+
+impl<K> MergePlan<'_, K> {
+    /// The paths the local replica changed, whether the changes are pushed or conflict.
+    pub fn local_paths(&self) -> Vec<&[K]> {
+        let conflicting = self.conflicts.iter().flat_map(|conflict| &conflict.local);
+        self.push
+            .iter()
+            .chain(conflicting)
+            .flat_map(Change::paths)
+            .collect()
+    }
+
+    /// The paths the remote replica changed, whether the changes are pulled or conflict.
+    pub fn remote_paths(&self) -> Vec<&[K]> {
+        let conflicting = self.conflicts.iter().flat_map(|conflict| &conflict.remote);
+        self.pull
+            .iter()
+            .chain(conflicting)
+            .flat_map(Change::paths)
+            .collect()
+    }
+}
+
+/// Both sides changed the same path, or something below a path the other side removed, in different ways.
+/// None of the changes are part of the push or pull of the plan.
+#[derive(Debug)]
+pub struct Conflict<'a, K> {
+    pub local: Vec<Change<K, &'a MerkleEntry>>,
+    pub remote: Vec<Change<K, &'a MerkleEntry>>,
+}
+
+/// Indices of the local and remote changes that are part of a conflict.
+#[derive(Debug, Default)]
+struct ConflictIndices {
+    local: BTreeSet<usize>,
+    remote: BTreeSet<usize>,
+}
+impl ConflictIndices {
+    fn insert(&mut self, local: usize, remote: usize) {
+        self.local.insert(local);
+        self.remote.insert(remote);
+    }
+}
+
+/// What a change leaves at one of its paths. Directories are compared without their children,
+/// which have changes of their own.
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Missing,
+    File(BHash),
+    Directory,
+}
+
+impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> MerkleTree<K> {
+    /// Three-way merge of `local` and `remote` with `self` as the state they were last synced at.
+    ///
+    /// Changes only one side made are pushed or pulled, changes both sides made the same way are dropped.
+    /// Changes to the same path with different results conflict, unless the version of one side's file
+    /// already includes the other side's. Changes below a path the other side removed conflict as well.
+    pub fn merge_plan<'a>(&'a self, local: &'a Self, remote: &'a Self) -> MergePlan<'a, K> {
+        let local_changes = self.find_difference(local);
+        let remote_changes = self.find_difference(remote);
+        let local_by_path = changes_by_path(&local_changes);
+        let remote_by_path = changes_by_path(&remote_changes);
+
+        // changes at the same path, and changes below a removed path, by the path they conflict at
+        let mut conflicts = BTreeMap::<&[K], ConflictIndices>::new();
+        let mut converged = (HashSet::new(), HashSet::new());
+        for (path, local_indices) in &local_by_path {
+            let Some(remote_indices) = remote_by_path.get(path) else {
+                continue;
+            };
+            // a file that was synced before being changed again already includes the other side's change
+            let causality = local.causality(remote, path);
+            for &i in local_indices {
+                for &j in remote_indices {
+                    if outcomes(&local_changes[i]) == outcomes(&remote_changes[j]) {
+                        converged.0.insert(i);
+                        converged.1.insert(j);
+                    } else if causality == Causality::Descendant {
+                        converged.1.insert(j);
+                    } else if causality == Causality::Ancestor {
+                        converged.0.insert(i);
+                    } else {
+                        conflicts.entry(path).or_default().insert(i, j);
+                    }
+                }
+            }
+        }
+        for (i, change) in local_changes.iter().enumerate() {
+            if let Some(removed) = removed_path(change) {
+                for j in changes_below(&remote_by_path, removed) {
+                    conflicts.entry(removed).or_default().insert(i, j);
+                }
+            }
+        }
+        for (j, change) in remote_changes.iter().enumerate() {
+            if let Some(removed) = removed_path(change) {
+                for i in changes_below(&local_by_path, removed) {
+                    conflicts.entry(removed).or_default().insert(i, j);
+                }
+            }
+        }
+
+        // a conflict below another one is part of it, descendants are sorted right after their ancestor
+        let mut merged: Vec<(&[K], ConflictIndices)> = Vec::new();
+        for (path, indices) in conflicts {
+            match merged.last_mut() {
+                Some((ancestor, conflict)) if path.starts_with(ancestor) => {
+                    conflict.local.extend(indices.local);
+                    conflict.remote.extend(indices.remote);
+                }
+                _ => merged.push((path, indices)),
+            }
+        }
+
+        let mut conflicted = (HashSet::new(), HashSet::new());
+        for (_, indices) in &merged {
+            conflicted.0.extend(&indices.local);
+            conflicted.1.extend(&indices.remote);
+        }
+        let conflicts = merged
+            .into_iter()
+            .map(|(_, indices)| Conflict {
+                local: indices
+                    .local
+                    .into_iter()
+                    .map(|i| local_changes[i].clone())
+                    .collect(),
+                remote: indices
+                    .remote
+                    .into_iter()
+                    .map(|j| remote_changes[j].clone())
+                    .collect(),
+            })
+            .collect();
+
+        let keep = |skip: (&HashSet<usize>, &HashSet<usize>), changes: Vec<_>| {
+            changes
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| !skip.0.contains(i) && !skip.1.contains(i))
+                .map(|(_, change)| change)
+                .collect()
+        };
+        MergePlan {
+            push: keep((&conflicted.0, &converged.0), local_changes),
+            pull: keep((&conflicted.1, &converged.1), remote_changes),
+            conflicts,
+        }
+    }
+}
+
+/// Indices of the changes by every path they affect, sorted so the descendants of a path follow right after it.
+fn changes_by_path<K: Ord, E>(changes: &[Change<K, E>]) -> BTreeMap<&[K], Vec<usize>> {
+    let mut by_path = BTreeMap::<_, Vec<_>>::new();
+    for (i, change) in changes.iter().enumerate() {
+        match change {
+            Change::Moved { from, to, .. } => {
+                by_path.entry(from.as_slice()).or_default().push(i);
+                by_path.entry(to.as_slice()).or_default().push(i);
+            }
+            Change::Added { path, .. }
+            | Change::Modified { path, .. }
+            | Change::Deleted { path, .. }
+            | Change::TypeChanged { path, .. }
+            | Change::Copied { to: path, .. } => {
+                by_path.entry(path.as_slice()).or_default().push(i)
+            }
+        }
+    }
+    by_path
+}
+
+/// The indices of changes strictly below `path`.
+fn changes_below<'a, K: Ord>(
+    by_path: &'a BTreeMap<&[K], Vec<usize>>,
+    path: &'a [K],
+) -> impl Iterator<Item = usize> + 'a {
+    by_path
+        .range(path..)
+        .take_while(move |(other, _)| other.starts_with(path))
+        .filter(move |(other, _)| other.len() > path.len())
+        .flat_map(|(_, indices)| indices.iter().copied())
+}
+
+/// The path whose previous content the change removes, if any.
+fn removed_path<K, E>(change: &Change<K, E>) -> Option<&[K]> {
+    match change {
+        Change::Deleted { path, .. } | Change::TypeChanged { path, .. } => Some(path),
+        Change::Moved { from, .. } => Some(from),
+        Change::Added { .. } | Change::Modified { .. } | Change::Copied { .. } => None,
+    }
+}
+
+/// What is left at every path a change affects.
+fn outcomes<'a, K>(change: &'a Change<K, &MerkleEntry>) -> Vec<(&'a [K], Outcome)> {
+    let outcome = |entry: &MerkleEntry| match entry {
+        MerkleEntry::File(_) => Outcome::File(entry.get_hash()),
+        MerkleEntry::Directory(_) => Outcome::Directory,
+    };
+    match change {
+        Change::Added { path, new }
+        | Change::Modified { path, new, .. }
+        | Change::TypeChanged { path, new, .. }
+        | Change::Copied { to: path, new, .. } => vec![(path.as_slice(), outcome(new))],
+        Change::Deleted { path, .. } => vec![(path.as_slice(), Outcome::Missing)],
+        Change::Moved { from, to, new, .. } => {
+            vec![
+                (from.as_slice(), Outcome::Missing),
+                (to.as_slice(), outcome(new)),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        datastructures::version_vector::ReplicaId,
+        test_util::{rescan, segments, TempDir},
+    };
+
+    /// Both replicas change a file they synced before, with the given modification times.
+    /// If `remote_saw_local`, the remote got the local change before making its own.
+    /// Returns the number of pushed and pulled changes and of conflicts.
+    fn change_both(
+        local_mtime: u64,
+        remote_mtime: u64,
+        remote_saw_local: bool,
+    ) -> (usize, usize, usize) {
+        let local_dir = TempDir::new();
+        let remote_dir = TempDir::new();
+        let path = segments("file.txt");
+        local_dir.write("file.txt", "synced");
+        let mut local = local_dir.scan();
+        let base = local.clone_as(local.replica());
+        let mut remote = local.clone_as(ReplicaId::generate());
+
+        local_dir.write("file.txt", "local");
+        local_dir.set_modified("file.txt", local_mtime);
+        rescan(&mut local, local_dir.path());
+        if remote_saw_local {
+            let version = local.version(&path).unwrap().clone();
+            remote
+                .insert_synced(&path, local.get(&path).clone(), &version)
+                .unwrap();
+        }
+        remote_dir.write("file.txt", "remote");
+        remote_dir.set_modified("file.txt", remote_mtime);
+        remote.insert(
+            &path,
+            remote_dir
+                .entry("file.txt")
+                .with_path(local.local_path(&path)),
+        );
+
+        let plan = base.merge_plan(&local, &remote);
+        (plan.push.len(), plan.pull.len(), plan.conflicts.len())
+    }
+
+    #[test]
+    fn concurrent_changes_conflict_regardless_of_modification_times() {
+        assert_eq!(change_both(1_000, 2_000, false), (0, 0, 1));
+        assert_eq!(change_both(2_000, 1_000, false), (0, 0, 1));
+    }
+
+    #[test]
+    fn change_that_includes_the_other_wins_regardless_of_modification_times() {
+        assert_eq!(change_both(1_000, 2_000, true), (0, 1, 0));
+        assert_eq!(change_both(2_000, 1_000, true), (0, 1, 0));
+    }
+}
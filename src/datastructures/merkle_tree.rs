@@ -2,33 +2,209 @@
 
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File},
     hash::Hash,
-    path::Path,
-    ptr::NonNull,
-    time::UNIX_EPOCH,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
 };
 
+use bincode::Options;
 use blake3::Hash as BHash;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::filesystem::data::MerkleEntry;
+use super::{
+    change::Change,
+    event::TreeEvent,
+    version_vector::{Causality, ReplicaId, VersionVector},
+};
+use crate::{
+    error::SyncronError,
+    filesystem::{data::MerkleEntry, own_writes::OwnWrites, scan::ScanMessage},
+};
+
+/// Version of the on-disk index format. Increment whenever [IndexHeader], [IndexNode] or the hashing of nodes change.
+const INDEX_VERSION: u32 = 7;
+
+/// Key context for hashing directories, so their hashes never equal the hash of a file's content.
+const DIRECTORY_CONTEXT: &str = "syncron 2024 directory listing";
+/// Marks the kind of an entry when hashing directories.
+const FILE_TAG: u8 = 0;
+const DIRECTORY_TAG: u8 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct IndexHeader {
+    version: u32,
+    replica: ReplicaId,
+    clock: u64,
+    root_hash: BHash,
+    /// hash of the serialized nodes following the header
+    checksum: BHash,
+}
 
-// TODO: figure out how to handle copied files (currently hashes would overwrite each other)
-type TreeNodeDiffResult<'a> = HashMap<&'a BHash, (u64, &'a MerkleEntry)>;
+/// A node as stored in the index. Nodes are stored in pre-order, each followed by its `child_count` children.
+#[derive(Serialize, Deserialize)]
+struct IndexNode<S, E, V> {
+    segment: S,
+    child_count: usize,
+    hash: BHash,
+    last_modified: u64,
+    data: E,
+    version: V,
+}
+
+/// Intermediate result of comparing two trees.
+/// Added and deleted subtrees are only recorded at their topmost node, so moves can be detected before expanding them.
+struct TreeNodeDiff<'a, K: AsRef<[u8]>> {
+    changes: Vec<Change<K, &'a MerkleEntry>>,
+    added: Vec<(Vec<K>, &'a TreeNode<K>)>,
+    deleted: Vec<(Vec<K>, &'a TreeNode<K>)>,
+}
 
 pub struct MerkleTree<K: AsRef<[u8]>> {
     root: TreeNode<K>,
+    /// the replica this tree belongs to, local modifications are recorded in the versions of files with it
+    replica: ReplicaId,
+    /// counts the local modifications
+    clock: u64,
+    /// receives the changes that have to be done on disk, see [subscribe](Self::subscribe)
+    subscriber: Option<Sender<TreeEvent<K>>>,
+    /// changes on disk that were made for this tree and are not local changes
+    own_writes: OwnWrites,
 }
 impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> MerkleTree<K> {
+    /// Creates a tree for a new replica.
     pub fn new(root_segment: K, data: MerkleEntry) -> MerkleTree<K> {
         MerkleTree {
-            root: TreeNode {
-                parent: Option::None,
-                children: BTreeMap::new(),
-                segment: root_segment,
-                hash: data.get_hash(),
-                last_modified: data.get_last_modified(),
-                data,
-            },
+            root: TreeNode::new(root_segment, data),
+            replica: ReplicaId::generate(),
+            clock: 0,
+            subscriber: None,
+            own_writes: OwnWrites::default(),
+        }
+    }
+
+    pub fn replica(&self) -> ReplicaId {
+        self.replica
+    }
+
+    /// A copy of the tree that belongs to `replica`, e.g. as the starting point of what is known about a remote.
+    pub fn clone_as(&self, replica: ReplicaId) -> Self {
+        MerkleTree {
+            root: self.root.clone(),
+            replica,
+            clock: 0,
+            subscriber: None,
+            own_writes: OwnWrites::default(),
+        }
+    }
+
+    /// Publishes the changes made with [try_insert](Self::try_insert), [insert_synced](Self::insert_synced),
+    /// [try_move](Self::try_move), [try_copy](Self::try_copy) and [try_remove](Self::try_remove) from now on,
+    /// so they can be done on disk. Scans are not published, they only record what is on disk already.
+    ///
+    /// Replaces the previous subscriber.
+    pub fn subscribe(&mut self) -> Receiver<TreeEvent<K>> {
+        let (sender, receiver) = channel();
+        self.subscriber = Some(sender);
+        receiver
+    }
+
+    pub fn unsubscribe(&mut self) {
+        self.subscriber = None;
+    }
+
+    /// The record of what was changed on disk to catch up with the tree. Scans and the file watcher skip these changes.
+    pub fn own_writes(&self) -> &OwnWrites {
+        &self.own_writes
+    }
+
+    fn publish(&mut self, event: TreeEvent<K>) {
+        if let Some(subscriber) = &self.subscriber {
+            // nobody listens anymore
+            if subscriber.send(event).is_err() {
+                self.subscriber = None;
+            }
+        }
+    }
+
+    /// Publishes `event`, which puts a new entry at `segments`. Missing parents are created
+    /// and what is at `segments` is deleted before.
+    fn publish_at(&mut self, segments: &[K], event: TreeEvent<K>) {
+        if self.subscriber.is_none() {
+            return;
+        }
+        let mut events = (1..segments.len())
+            .filter(|depth| self.root.find(&segments[..*depth]).is_none())
+            .map(|depth| TreeEvent::CreateDir(segments[..depth].to_vec()))
+            .collect::<Vec<_>>();
+        if let Some(node) = self.root.find(segments) {
+            events.push(delete_event(segments, &node.data));
+        }
+        events.push(event);
+        for event in events {
+            self.publish(event);
+        }
+    }
+
+    /// Publishes the events to insert `data` at `segments`. A file whose content did not change and
+    /// a directory replacing a directory need nothing on disk.
+    fn publish_insert(&mut self, segments: &[K], data: &MerkleEntry) {
+        let path = segments.to_vec();
+        let hash = data.get_hash();
+        let event = match (self.root.find(segments), data) {
+            (Some(node), MerkleEntry::File(_)) if matches!(node.data, MerkleEntry::File(_)) => {
+                if node.hash != hash {
+                    self.publish(TreeEvent::UpdateFile { path, hash });
+                }
+                return;
+            }
+            (Some(node), MerkleEntry::Directory(_))
+                if matches!(node.data, MerkleEntry::Directory(_)) =>
+            {
+                return
+            }
+            (_, MerkleEntry::File(_)) => TreeEvent::CreateFile { path, hash },
+            (_, MerkleEntry::Directory(_)) => TreeEvent::CreateDir(path),
+        };
+        self.publish_at(segments, event);
+    }
+
+    /// The node at `segments` with everything below it, to put it back with [restore](Self::restore).
+    pub fn snapshot(&self, segments: &[K]) -> Snapshot<K> {
+        Snapshot(self.root.find(segments).cloned())
+    }
+
+    /// Puts the node at `segments` back to how it was in `snapshot`, e.g. after its change could not be done on disk.
+    /// Nothing is published.
+    pub fn restore(&mut self, segments: &[K], snapshot: Snapshot<K>) {
+        if segments.is_empty() {
+            return;
+        }
+        match snapshot.0 {
+            Some(node) => self.root.insert_node(segments, node),
+            None => {
+                self.root.remove(segments);
+            }
+        }
+    }
+
+    /// The version of the file at `segments`. Directories don't have versions.
+    pub fn version(&self, segments: &[K]) -> Option<&VersionVector> {
+        self.root
+            .find(segments)
+            .filter(|node| matches!(node.data, MerkleEntry::File(_)))
+            .map(|node| &node.version)
+    }
+
+    /// How the file at `segments` relates to the file at the same path in `other`, by their versions.
+    /// Since versions don't depend on clocks, neither do the results.
+    ///
+    /// Unless both trees have a file there, the changes that led to their entries are [Concurrent](Causality::Concurrent).
+    pub fn causality(&self, other: &Self, segments: &[K]) -> Causality {
+        match (self.version(segments), other.version(segments)) {
+            (Some(version), Some(other)) => version.compare(other),
+            _ => Causality::Concurrent,
         }
     }
 
@@ -36,74 +212,771 @@ impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> MerkleTree<K> {
         &self.root.get(segments).data
     }
 
+    /// Like [get](Self::get), but returns `None` if there is no node at `segments`.
+    pub fn try_get(&self, segments: &[K]) -> Option<&MerkleEntry> {
+        self.root.find(segments).map(|node| &node.data)
+    }
+
     pub fn get_hash(&self, segments: &[K]) -> &BHash {
         &self.root.get(segments).hash
     }
 
+    /// The modification time of the file at `segments`, or of the latest file below the directory.
+    pub fn get_last_modified(&self, segments: &[K]) -> u64 {
+        self.root.get(segments).last_modified
+    }
+
+    /// The path of `segments` on disk, below the path of the root entry.
+    pub fn local_path(&self, segments: &[K]) -> PathBuf {
+        let mut path = self.root.data.get_path().to_owned();
+        path.extend(
+            segments
+                .iter()
+                .map(|segment| String::from_utf8_lossy(segment.as_ref()).into_owned()),
+        );
+        path
+    }
+
+    /// The children of the directory at `segments` with their hashes, or `None` if there is no such directory.
+    pub fn children(
+        &self,
+        segments: &[K],
+    ) -> Option<impl Iterator<Item = (&K, &BHash, &MerkleEntry)>> {
+        let node = self
+            .root
+            .find(segments)
+            .filter(|node| matches!(node.data, MerkleEntry::Directory(_)))?;
+        Some(
+            node.children()
+                .map(|(segment, child)| (segment, &child.hash, &child.data)),
+        )
+    }
+
+    /// Iterates over the entries of all nodes, including the root.
+    pub fn entries(&self) -> impl Iterator<Item = &MerkleEntry> {
+        self.nodes().map(|node| &node.data)
+    }
+
+    /// Iterates over all directories with the latest modification of a file below them.
+    pub fn directories(&self) -> impl Iterator<Item = (&Path, u64)> {
+        self.nodes()
+            .filter(|node| matches!(node.data, MerkleEntry::Directory(_)))
+            .map(|node| (node.data.get_path(), node.last_modified))
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &TreeNode<K>> {
+        let mut stack = vec![&self.root];
+        std::iter::from_fn(move || {
+            let node = stack.pop()?;
+            stack.extend(node.children().map(|(_, child)| child));
+            Some(node)
+        })
+    }
+
+    /// Inserts or replaces the node at `segments`. Missing parents are created as directories.
+    /// A directory replacing a directory keeps its children.
+    ///
+    /// Panics if a parent is a file or `segments` is empty, see [try_insert](Self::try_insert).
     pub fn insert(&mut self, segments: &[K], data: MerkleEntry) {
+        match self.try_insert(segments, data) {
+            Ok(()) => {}
+            Err(InsertError::EmptyPath) => panic!("unable to replace the root"),
+            Err(InsertError::NotADirectory(_)) => panic!("parent is not a directory"),
+        }
+    }
+
+    /// Inserts or replaces the node at `segments` as a local modification. Missing parents are created
+    /// as directories with placeholder entries until the directories themselves are inserted.
+    pub fn try_insert(&mut self, segments: &[K], data: MerkleEntry) -> Result<(), InsertError<K>> {
+        self.check_parents(segments)?;
+        self.publish_insert(segments, &data);
+        self.insert_local(segments, data);
+        Ok(())
+    }
+
+    /// Like [try_insert](Self::try_insert), but for a file synced from another replica.
+    /// The file gets the modifications of both `version` and the file it replaces.
+    pub fn insert_synced(
+        &mut self,
+        segments: &[K],
+        data: MerkleEntry,
+        version: &VersionVector,
+    ) -> Result<(), InsertError<K>> {
+        self.check_parents(segments)?;
+        self.publish_insert(segments, &data);
+        self.insert_versioned(segments, data).merge(version);
+        Ok(())
+    }
+
+    fn check_parents(&self, segments: &[K]) -> Result<(), InsertError<K>> {
+        let Some((_, parents)) = segments.split_last() else {
+            return Err(InsertError::EmptyPath);
+        };
+        let mut node = &self.root;
+        for (depth, segment) in parents.iter().enumerate() {
+            match node.children.get(segment) {
+                Some(child) if matches!(child.data, MerkleEntry::Directory(_)) => node = child,
+                Some(_) => return Err(InsertError::NotADirectory(segments[..=depth].to_vec())),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts `data` and records a local modification in its version if it is a file.
+    fn insert_local(&mut self, segments: &[K], data: MerkleEntry) {
+        let is_file = matches!(data, MerkleEntry::File(_));
+        let (replica, clock) = (self.replica, self.clock + 1);
+        let version = self.insert_versioned(segments, data);
+        if is_file {
+            version.record(replica, clock);
+            self.clock = clock;
+        }
+    }
+
+    /// Inserts `data` and returns the version of the new node, which continues the version of a replaced file.
+    fn insert_versioned(&mut self, segments: &[K], data: MerkleEntry) -> &mut VersionVector {
+        let previous = self.version(segments).cloned().unwrap_or_default();
+        let is_file = matches!(data, MerkleEntry::File(_));
         self.root.insert(segments, data);
+        let node = self.root.find_mut(segments).unwrap();
+        if is_file {
+            node.version = previous;
+        }
+        &mut node.version
     }
 
+    /// Moves the node at `from` and everything below it to `to`, replacing what is there, e.g. after it was renamed on disk.
+    /// The entries are moved below the new path and files keep their versions.
+    /// Returns `false` if there is no node at `from` or `to` is below it.
+    pub fn try_move(&mut self, from: &[K], to: &[K]) -> Result<bool, InsertError<K>> {
+        self.check_parents(to)?;
+        if from.is_empty() || to.starts_with(from) || self.root.find(from).is_none() {
+            return Ok(false);
+        }
+        self.publish_at(
+            to,
+            TreeEvent::Move {
+                from: from.to_vec(),
+                to: to.to_vec(),
+            },
+        );
+        let node = self.root.remove(from).unwrap();
+        self.insert_node(to, node);
+        Ok(true)
+    }
+
+    /// Like [try_move](Self::try_move), but keeps the node at `from`.
+    pub fn try_copy(&mut self, from: &[K], to: &[K]) -> Result<bool, InsertError<K>> {
+        self.check_parents(to)?;
+        if to.starts_with(from) {
+            return Ok(false);
+        }
+        let Some(node) = self.root.find(from).cloned() else {
+            return Ok(false);
+        };
+        self.publish_at(
+            to,
+            TreeEvent::Copy {
+                from: from.to_vec(),
+                to: to.to_vec(),
+            },
+        );
+        self.insert_node(to, node);
+        Ok(true)
+    }
+
+    fn insert_node(&mut self, segments: &[K], mut node: TreeNode<K>) {
+        node.segment = segments.last().unwrap().clone();
+        node.relocate(&self.local_path(segments));
+        self.root.insert_node(segments, node);
+    }
+
+    /// Removes the node at `segments` and everything below it. Panics if there is no such node.
     pub fn remove(&mut self, segments: &[K]) {
-        self.root.remove(segments);
+        self.try_remove(segments).expect("no such node");
     }
 
-    pub fn find_difference<'a>(
-        &'a self,
-        other: &'a Self,
-    ) -> Option<(Vec<&'a Path>, Vec<&'a Path>)> {
-        let (diff1, diff2) = match self.root.find_difference(&other.root) {
-            Some(inner) => inner,
-            None => return None,
+    /// Removes the node at `segments` and everything below it.
+    /// Returns its entry, or `None` if there is no such node. The root can't be removed.
+    pub fn try_remove(&mut self, segments: &[K]) -> Option<MerkleEntry> {
+        let node = self.root.remove(segments)?;
+        self.publish(delete_event(segments, &node.data));
+        Some(node.data)
+    }
+
+    /// Finds all changes needed to get from `self` (the old state) to `other` (the new state).
+    ///
+    /// Subtrees that were removed at one path and added with identical content at another path are
+    /// reported as a single [Change::Moved] instead of their individual deletes and adds.
+    /// Subtrees that were added with the same content as an entry that did not change are reported as [Change::Copied].
+    pub fn find_difference<'a>(&'a self, other: &'a Self) -> Vec<Change<K, &'a MerkleEntry>> {
+        let mut diff = TreeNodeDiff {
+            changes: Vec::new(),
+            added: Vec::new(),
+            deleted: Vec::new(),
         };
+        self.root
+            .find_difference(&other.root, &mut Vec::new(), &mut diff);
+        let TreeNodeDiff {
+            mut changes,
+            added,
+            deleted,
+        } = diff;
 
-        // TODO: figure out what to do with moves
-        let keys1 = diff1.keys().collect::<HashSet<_>>();
-        let keys2 = diff2.keys().collect::<HashSet<_>>();
-        let moved_entries = keys1.intersection(&keys2).collect::<HashSet<_>>();
-        moved_entries.iter().for_each(|hash| {
-            let (l1, entry1) = diff1.get(**hash).unwrap();
-            let (l2, entry2) = diff2.get(**hash).unwrap();
-            if l1 < l2 {
-                println!("{:?} moved to {:?}", entry1.get_path(), entry2.get_path());
-            } else {
-                println!("{:?} moved to {:?}", entry2.get_path(), entry1.get_path());
-            };
+        let deleted_nodes = deleted.iter().map(|(_, node)| *node).collect::<Vec<_>>();
+        let added_nodes = added.iter().map(|(_, node)| *node).collect::<Vec<_>>();
+        let sources = match_moves(&deleted_nodes, &added_nodes);
+        let mut moved = vec![false; deleted.len()];
+
+        let mut copy_sources = HashMap::new();
+        if sources.iter().any(Option::is_none) {
+            self.root
+                .collect_unchanged(&other.root, &mut Vec::new(), &mut copy_sources);
+        }
+        let mut added_changes = Vec::new();
+        for ((path, node), source) in added.into_iter().zip(sources) {
+            match source {
+                Some(i) => {
+                    moved[i] = true;
+                    changes.push(Change::Moved {
+                        from: deleted[i].0.clone(),
+                        to: path,
+                        old: &deleted[i].1.data,
+                        new: &node.data,
+                    });
+                }
+                None => node.collect_added(&mut path.clone(), &copy_sources, &mut added_changes),
+            }
+        }
+        changes.extend(added_changes);
+
+        let mut deleted_nodes = Vec::new();
+        deleted
+            .into_iter()
+            .zip(moved)
+            .filter(|(_, moved)| !moved)
+            .for_each(|((path, node), _)| {
+                node.collect_post_order(&mut path.clone(), &mut deleted_nodes)
+            });
+        changes.extend(
+            deleted_nodes
+                .into_iter()
+                .map(|(path, node)| Change::Deleted {
+                    path,
+                    old: &node.data,
+                }),
+        );
+
+        changes
+    }
+}
+
+/// Pairs subtrees that were deleted with subtrees that were added with the same content.
+/// Returns for every added subtree the index of the deleted subtree it was moved from.
+///
+/// Empty directories are skipped since they all share the same hash.
+/// Subtrees with the same content are matched in order, the remaining added ones are reported as copies or adds.
+fn match_moves<K: Eq + Ord + Clone + Hash + AsRef<[u8]>>(
+    deleted: &[&TreeNode<K>],
+    added: &[&TreeNode<K>],
+) -> Vec<Option<usize>> {
+    let mut deleted_by_hash: HashMap<&BHash, Vec<usize>> = HashMap::new();
+    deleted
+        .iter()
+        .enumerate()
+        .filter(|(_, node)| !node.is_empty_directory())
+        .for_each(|(i, node)| deleted_by_hash.entry(&node.hash).or_default().push(i));
+
+    added
+        .iter()
+        .map(|node| {
+            let candidates = deleted_by_hash.get_mut(&node.hash)?;
+            let index = candidates
+                .iter()
+                .position(|&i| is_same_kind(&deleted[i].data, &node.data))?;
+            Some(candidates.remove(index))
+        })
+        .collect()
+}
+
+fn is_same_kind(a: &MerkleEntry, b: &MerkleEntry) -> bool {
+    matches!(
+        (a, b),
+        (MerkleEntry::File(_), MerkleEntry::File(_))
+            | (MerkleEntry::Directory(_), MerkleEntry::Directory(_))
+    )
+}
+
+impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]> + Serialize + DeserializeOwned> MerkleTree<K> {
+    /// Writes the tree to an index file. The file is replaced atomically, so a crash never leaves a partial index.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+
+        let mut nodes = Vec::new();
+        self.root.collect_index_nodes(&mut nodes);
+        let options = bincode::DefaultOptions::new();
+        let nodes = options.serialize(&nodes).map_err(invalid_data)?;
+        let header = IndexHeader {
+            version: INDEX_VERSION,
+            replica: self.replica,
+            clock: self.clock,
+            root_hash: self.root.hash,
+            checksum: blake3::hash(&nodes),
+        };
+        options
+            .serialize_into(&mut writer, &header)
+            .map_err(invalid_data)?;
+        writer.write_all(&nodes)?;
+
+        writer.into_inner()?.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Reads a tree from an index file written by [MerkleTree::save] for the sync root that is at `root` now.
+    /// The entries are moved below `root` in case the sync root was moved or renamed since.
+    ///
+    /// Fails if the index has a different version or the stored hashes do not match the stored entries.
+    pub fn load(path: &Path, root: &Path) -> io::Result<MerkleTree<K>> {
+        let file = File::open(path)?;
+        // a corrupt length prefix must not make us allocate more than the file could possibly contain
+        let options = bincode::DefaultOptions::new().with_limit(file.metadata()?.len());
+        let mut reader = BufReader::new(file);
+
+        let header: IndexHeader = options
+            .deserialize_from(&mut reader)
+            .map_err(invalid_data)?;
+        if header.version != INDEX_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported index version {} (expected {INDEX_VERSION})",
+                header.version
+            )));
+        }
+        let mut nodes = Vec::new();
+        reader.read_to_end(&mut nodes)?;
+        if blake3::hash(&nodes) != header.checksum {
+            return Err(invalid_data("index checksum mismatch"));
+        }
+        let nodes: Vec<IndexNode<K, MerkleEntry, VersionVector>> =
+            options.deserialize(&nodes).map_err(invalid_data)?;
+
+        let mut nodes = nodes.into_iter();
+        let mut node = TreeNode::from_index_nodes(&mut nodes)?;
+        if nodes.next().is_some() {
+            return Err(invalid_data("index contains nodes outside of the tree"));
+        }
+        if node.hash != header.root_hash {
+            return Err(invalid_data("index root hash does not match its entries"));
+        }
+        if node.data.get_path() != root {
+            node.relocate(root);
+        }
+        Ok(MerkleTree {
+            root: node,
+            replica: header.replica,
+            clock: header.clock,
+            subscriber: None,
+            own_writes: OwnWrites::default(),
+        })
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]> + for<'a> From<&'a str>> MerkleTree<K> {
+    /// Updates the tree in place from a full scan of `root`, as produced by [walk_directory](crate::filesystem::scan::walk_directory).
+    ///
+    /// New paths are inserted, changed paths are updated and paths missing from the scan are removed.
+    /// Nodes that did not change keep their hash and last modified timestamp.
+    ///
+    /// Paths that failed to be read keep their previous state, as do all paths if the scan itself failed
+    /// or ended before it was [done](ScanMessage::Done).
+    /// Files that are [pending](MerkleEntry::is_pending) are expected to be written soon, so they are kept
+    /// even if the scan did not find them or found other content. The same goes for [own_writes](Self::own_writes).
+    pub fn apply_scan(&mut self, root: &Path, receiver: Receiver<ScanMessage>) -> ScanReport<K> {
+        self.apply_subtree_scan(root, Path::new(""), &[], receiver)
+    }
+
+    /// Like [apply_scan](Self::apply_scan), but for a scan of `subtree` (relative to `root`) as produced by
+    /// [walk_subtree](crate::filesystem::scan::walk_subtree). Only the nodes below `subtree` are replaced,
+    /// except for the directories in `skip` (relative to `root`) which keep their previous state.
+    ///
+    /// Nothing is changed if `subtree` is not a directory in the tree.
+    pub fn apply_subtree_scan(
+        &mut self,
+        root: &Path,
+        subtree: &Path,
+        skip: &[PathBuf],
+        receiver: Receiver<ScanMessage>,
+    ) -> ScanReport<K> {
+        let scanned = root.join(subtree);
+        let subtree = path_segments::<K>(Path::new(""), subtree).filter(|subtree| {
+            matches!(
+                self.root.find(subtree).map(|node| &node.data),
+                Some(MerkleEntry::Directory(_))
+            )
         });
+        let Some(subtree) = subtree else {
+            return ScanReport {
+                changes: Vec::new(),
+                errors: receiver
+                    .into_iter()
+                    .filter_map(|message| match message {
+                        ScanMessage::Error(err) => Some(err),
+                        ScanMessage::Entry(_) | ScanMessage::Done => None,
+                    })
+                    .collect(),
+            };
+        };
+        let mut changes = Vec::new();
+        let mut errors = Vec::new();
+        let mut seen = HashSet::new();
+        // paths whose current state is unknown and the scan can't tell if they were deleted
+        let mut failed = skip
+            .iter()
+            .filter_map(|path| path_segments::<K>(Path::new(""), path))
+            .collect::<HashSet<_>>();
+        let mut complete = true;
+        // added paths in scan order and the topmost nodes of added subtrees
+        let mut added = Vec::new();
+        let mut added_set = HashSet::new();
+        let mut added_tops = Vec::new();
+
+        self.own_writes.expire();
+        let mut done = false;
+        while let Ok(message) = receiver.recv() {
+            let entry = match message {
+                ScanMessage::Entry(entry) => entry,
+                ScanMessage::Done => {
+                    done = true;
+                    break;
+                }
+                ScanMessage::Error(err) => {
+                    let path = err.path().and_then(|path| path.strip_prefix(root).ok());
+                    match (&err, path) {
+                        // without the scanned directory or outside of it we know nothing about the tree
+                        (_, Some(path))
+                            if path_segments::<K>(Path::new(""), path).as_ref()
+                                == Some(&subtree) =>
+                        {
+                            complete = false
+                        }
+                        // wrong ignore patterns don't make the scan incomplete
+                        (SyncronError::Ignore { .. }, _) => {}
+                        (_, None) => complete = false,
+                        // a vanished path is simply not part of the scan
+                        (SyncronError::Vanished(_) | SyncronError::Unsupported(_), _) => {}
+                        (_, Some(path)) => failed.extend(path_segments::<K>(Path::new(""), path)),
+                    }
+                    errors.push(err);
+                    continue;
+                }
+            };
+            // names that are not UTF-8 are reported by the scan itself
+            let Some(segments) = path_segments::<K>(root, entry.get_path()) else {
+                continue;
+            };
+            if segments.len() <= subtree.len() || !segments.starts_with(&subtree) {
+                continue;
+            }
+            if self.own_writes.is_own(entry.get_path(), Some(&entry)) {
+                // the tree already has what we wrote, or what is being written
+                failed.insert(segments);
+                continue;
+            }
+
+            if !self.upsert(&segments, entry, &mut changes) {
+                if !added_set.contains(&segments[..segments.len() - 1]) {
+                    added_tops.push(segments.clone());
+                }
+                added.push(segments.clone());
+                added_set.insert(segments.clone());
+            }
+            seen.insert(segments);
+        }
+        if !done {
+            complete = false;
+            errors.push(SyncronError::ScanAborted(scanned.clone()));
+        }
+
+        // Everything that was not part of the scan is gone
+        let mut deleted_tops = Vec::new();
+        let in_flight = self.own_writes.in_flight();
+        if complete && !scanned.ancestors().any(|path| in_flight.contains(path)) {
+            self.root.get(&subtree).collect_unseen(
+                &mut subtree.clone(),
+                &seen,
+                &failed,
+                &in_flight,
+                &mut deleted_tops,
+            );
+        }
+
+        let deleted_nodes = deleted_tops
+            .iter()
+            .map(|path| self.root.get(path))
+            .collect::<Vec<_>>();
+        let added_nodes = added_tops
+            .iter()
+            .map(|path| self.root.get(path))
+            .collect::<Vec<_>>();
+        let sources = match_moves(&deleted_nodes, &added_nodes);
+        let mut moved = vec![false; deleted_tops.len()];
+        let mut moved_to = Vec::new();
+        for ((to, node), source) in added_tops.iter().zip(&added_nodes).zip(sources) {
+            if let Some(i) = source {
+                moved[i] = true;
+                moved_to.push(to);
+                changes.push(Change::Moved {
+                    from: deleted_tops[i].clone(),
+                    to: to.clone(),
+                    old: deleted_nodes[i].data.clone(),
+                    new: node.data.clone(),
+                });
+            }
+        }
+
+        changes.extend(
+            added
+                .into_iter()
+                .filter(|path| !moved_to.iter().any(|to| path.starts_with(to)))
+                .map(|path| {
+                    let new = self.root.get(&path).data.clone();
+                    Change::Added { path, new }
+                }),
+        );
+
+        let mut removed = Vec::new();
+        deleted_tops
+            .iter()
+            .zip(&deleted_nodes)
+            .zip(&moved)
+            .filter(|(_, moved)| !**moved)
+            .for_each(|((path, node), _)| node.collect_post_order(&mut path.clone(), &mut removed));
+        changes.extend(removed.into_iter().map(|(path, node)| Change::Deleted {
+            path,
+            old: node.data.clone(),
+        }));
 
-        Some((
-            diff1.values().map(|(_, entry)| entry.get_path()).collect(),
-            diff2.values().map(|(_, entry)| entry.get_path()).collect(),
-        ))
+        for path in deleted_tops {
+            self.root.remove(&path);
+        }
+        ScanReport { changes, errors }
+    }
+}
+
+impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]> + for<'a> From<&'a str>> MerkleTree<K> {
+    /// Inserts or updates a single entry below `root`, e.g. after a file watcher reported a change.
+    /// Paths whose parent is not in the tree are skipped, as are names that are not UTF-8.
+    pub fn update_entry(&mut self, root: &Path, entry: MerkleEntry) -> Vec<Change<K>> {
+        let segments = path_segments::<K>(root, entry.get_path()).unwrap_or_default();
+        let mut changes = Vec::new();
+        let Some((_, parent)) = segments.split_last() else {
+            return changes;
+        };
+        if self.root.find(parent).is_none() {
+            return changes;
+        }
+
+        let new = entry.clone();
+        if !self.upsert(&segments, entry, &mut changes) {
+            changes.push(Change::Added {
+                path: segments,
+                new,
+            });
+        }
+        changes
+    }
+
+    /// Removes `path` below `root` and everything below it, e.g. after a file watcher reported a delete.
+    pub fn remove_path(&mut self, root: &Path, path: &Path) -> Vec<Change<K>> {
+        let segments = path_segments::<K>(root, path).unwrap_or_default();
+        let mut removed = Vec::new();
+        match self.root.find(&segments) {
+            Some(node) if !segments.is_empty() => {
+                node.collect_post_order(&mut segments.clone(), &mut removed)
+            }
+            _ => return Vec::new(),
+        }
+        let changes = removed
+            .into_iter()
+            .map(|(path, node)| Change::Deleted {
+                path,
+                old: node.data.clone(),
+            })
+            .collect();
+        self.root.remove(&segments);
+        changes
+    }
+}
+
+impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> MerkleTree<K> {
+    /// Inserts or updates the entry at `segments`, pushing the changes to existing nodes.
+    /// Returns false if the path did not exist yet, in which case it was added.
+    fn upsert(&mut self, segments: &[K], entry: MerkleEntry, changes: &mut Vec<Change<K>>) -> bool {
+        let Some(node) = self.root.find(segments) else {
+            self.insert_local(segments, entry);
+            return false;
+        };
+        match (&node.data, &entry) {
+            (MerkleEntry::File(_), MerkleEntry::File(_)) if node.hash == entry.get_hash() => {
+                // only metadata changed, e.g. the file was touched, which still moves its modification time
+                self.insert_versioned(segments, entry);
+            }
+            // what is on disk is about to be replaced
+            _ if node.data.is_pending() => {}
+            (MerkleEntry::File(_), MerkleEntry::File(_)) => {
+                changes.push(Change::Modified {
+                    path: segments.to_vec(),
+                    old: node.data.clone(),
+                    new: entry.clone(),
+                });
+                self.insert_local(segments, entry);
+            }
+            (MerkleEntry::Directory(_), MerkleEntry::Directory(_)) => {}
+            _ => {
+                // children of a replaced directory are gone
+                let mut removed = Vec::new();
+                node.children().for_each(|(segment, child)| {
+                    child.collect_post_order(&mut child_path(segments, segment), &mut removed)
+                });
+                changes.extend(removed.into_iter().map(|(path, node)| Change::Deleted {
+                    path,
+                    old: node.data.clone(),
+                }));
+                changes.push(Change::TypeChanged {
+                    path: segments.to_vec(),
+                    old: node.data.clone(),
+                    new: entry.clone(),
+                });
+                self.insert_local(segments, entry);
+            }
+        }
+        true
     }
 }
-// SAFETY: All modifications require a mutable reference, therefore Tree is Send/Sync if its parts are Send/Sync.
-unsafe impl<K: Send + AsRef<[u8]>> Send for MerkleTree<K> {}
-unsafe impl<K: Sync + AsRef<[u8]>> Sync for MerkleTree<K> {}
 
+/// The event that deletes `entry` at `segments`.
+fn delete_event<K: Clone>(segments: &[K], entry: &MerkleEntry) -> TreeEvent<K> {
+    match entry {
+        MerkleEntry::File(_) => TreeEvent::DeleteFile(segments.to_vec()),
+        MerkleEntry::Directory(_) => TreeEvent::DeleteDir(segments.to_vec()),
+    }
+}
+
+/// A node with everything below it as it was, see [MerkleTree::snapshot].
+pub struct Snapshot<K: AsRef<[u8]>>(Option<TreeNode<K>>);
+
+/// Why [MerkleTree::try_insert] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertError<K> {
+    /// The root can't be replaced.
+    EmptyPath,
+    /// A parent of the path is not a directory.
+    NotADirectory(Vec<K>),
+}
+
+/// Result of [MerkleTree::apply_scan].
+#[derive(Debug)]
+pub struct ScanReport<K> {
+    /// changes that were applied to the tree
+    pub changes: Vec<Change<K>>,
+    /// paths that could not be scanned
+    pub errors: Vec<SyncronError>,
+}
+
+/// Splits a path below `root` into its segments.
+/// Returns `None` if `path` is not below `root` or a segment is not UTF-8.
+fn path_segments<K: for<'a> From<&'a str>>(root: &Path, path: &Path) -> Option<Vec<K>> {
+    path.strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|comp| comp.as_os_str().to_str().map(K::from))
+        .collect()
+}
+
+/// Nodes own their children. There are no parent links, every operation starts at the root
+/// and recomputes the hashes of the nodes it passed on the way back up.
+#[derive(Clone)]
 struct TreeNode<K: AsRef<[u8]>> {
-    parent: Option<NonNull<TreeNode<K>>>,
-    children: BTreeMap<K, NonNull<TreeNode<K>>>,
+    children: BTreeMap<K, TreeNode<K>>,
     segment: K,
     /// indicates if the contents of this node (its children and/or its data) changed
     hash: BHash,
     last_modified: u64,
     // TODO: this can probably be removed
     data: MerkleEntry,
+    /// modifications of a file by replica, empty for directories
+    version: VersionVector,
 }
 impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> TreeNode<K> {
+    fn new(segment: K, data: MerkleEntry) -> Self {
+        let mut node = TreeNode {
+            children: BTreeMap::new(),
+            segment,
+            hash: data.get_hash(),
+            last_modified: data.get_last_modified(),
+            data,
+            version: VersionVector::default(),
+        };
+        node.recompute_node();
+        node
+    }
+
     fn recompute_node(&mut self) {
-        if self.children.is_empty() {
-            return;
+        self.hash = self.compute_hash();
+        self.last_modified = self.compute_last_modified();
+    }
+
+    /// Computes the hash of this node from its content (file) or its children (directory).
+    ///
+    /// Only content goes into the hash, so equal trees have equal hashes on every host.
+    /// Directories are hashed in their own domain from the name and kind of every child, so no directory
+    /// has the same hash as a file, whatever the file contains.
+    fn compute_hash(&self) -> BHash {
+        if let MerkleEntry::File(_) = self.data {
+            return self.data.get_hash();
         }
-        let mut hasher = blake3::Hasher::new();
-        self.children.values().for_each(|child| unsafe {
-            let child = child.as_ref();
+        let mut hasher = blake3::Hasher::new_derive_key(DIRECTORY_CONTEXT);
+        self.children().for_each(|(segment, child)| {
+            let tag = match child.data {
+                MerkleEntry::File(_) => FILE_TAG,
+                MerkleEntry::Directory(_) => DIRECTORY_TAG,
+            };
+            hasher.update(&[tag]);
+            hasher.update(&(segment.as_ref().len() as u64).to_le_bytes());
+            hasher.update(segment.as_ref());
             hasher.update(child.hash.as_bytes());
-            hasher.update(child.segment.as_ref());
         });
-        self.hash = hasher.finalize();
-        self.last_modified = UNIX_EPOCH.elapsed().unwrap().as_secs();
+        hasher.finalize()
+    }
+
+    /// Files were modified when their content was, directories when the latest file below them was.
+    /// Empty directories have never been modified.
+    fn compute_last_modified(&self) -> u64 {
+        match self.data {
+            MerkleEntry::File(_) => self.data.get_last_modified(),
+            MerkleEntry::Directory(_) => self
+                .children()
+                .map(|(_, child)| child.last_modified)
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// Whether this node or a node below it is a [pending](MerkleEntry::is_pending) file.
+    fn has_pending(&self) -> bool {
+        self.data.is_pending() || self.children().any(|(_, child)| child.has_pending())
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        matches!(self.data, MerkleEntry::Directory(_)) && self.children.is_empty()
     }
 
     fn get(&self, segments: &[K]) -> &Self {
@@ -111,176 +984,639 @@ impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> TreeNode<K> {
             return self;
         }
 
-        let next_node = *self.children.get(&segments[0]).expect("not such node");
-        return unsafe { next_node.as_ref().get(&segments[1..]) };
+        let next_node = self.children.get(&segments[0]).expect("not such node");
+        next_node.get(&segments[1..])
+    }
+
+    fn find(&self, segments: &[K]) -> Option<&Self> {
+        match segments.split_first() {
+            None => Some(self),
+            Some((segment, rest)) => self.children.get(segment)?.find(rest),
+        }
+    }
+
+    fn find_mut(&mut self, segments: &[K]) -> Option<&mut Self> {
+        match segments.split_first() {
+            None => Some(self),
+            Some((segment, rest)) => self.children.get_mut(segment)?.find_mut(rest),
+        }
+    }
+
+    /// Inserts `node` at `segments`, replacing the node that is there and creating missing directories on the way.
+    fn insert_node(&mut self, segments: &[K], node: Self) {
+        let (segment, rest) = segments.split_first().expect("empty path");
+        if rest.is_empty() {
+            self.children.insert(segment.clone(), node);
+        } else {
+            let next_node = self.children.entry(segment.clone()).or_insert_with(|| {
+                let path = node.data.get_path().ancestors().nth(rest.len());
+                let placeholder = MerkleEntry::placeholder_directory(path.unwrap_or(Path::new("")));
+                TreeNode::new(segment.clone(), placeholder)
+            });
+            next_node.insert_node(rest, node);
+        }
+
+        self.recompute_node();
     }
 
+    /// Moves the entries of this node and its descendants below `path`.
+    fn relocate(&mut self, path: &Path) {
+        self.data = self.data.with_path(path.to_owned());
+        for (segment, child) in &mut self.children {
+            child.relocate(&path.join(String::from_utf8_lossy(segment.as_ref()).as_ref()));
+        }
+    }
+
+    /// Inserts `data` at `segments`, creating missing directories on the way.
     fn insert(&mut self, segments: &[K], data: MerkleEntry) {
-        if segments.len() == 1 {
-            let new_node = TreeNode {
-                parent: Some(self.into()),
-                children: BTreeMap::new(),
-                segment: segments[0].clone(),
-                hash: data.get_hash(),
-                last_modified: data.get_last_modified(),
-                data,
-            };
-            let child_ptr = unsafe { NonNull::new_unchecked(Box::into_raw(Box::new(new_node))) };
-            if let Some(old_node) = self.children.insert(segments[0].clone(), child_ptr) {
-                // SAFETY: This ensures the Box will be properly dropped after this scope, deallocating the memory.
-                let _ = unsafe { Box::from_raw(old_node.as_ptr()) };
+        let (segment, rest) = segments.split_first().expect("empty path");
+        if rest.is_empty() {
+            match self.children.get_mut(segment) {
+                // a directory keeps its content, e.g. when it is inserted after its children
+                Some(node)
+                    if matches!(
+                        (&node.data, &data),
+                        (MerkleEntry::Directory(_), MerkleEntry::Directory(_))
+                    ) =>
+                {
+                    node.data = data;
+                    node.recompute_node();
+                }
+                _ => {
+                    self.children
+                        .insert(segment.clone(), TreeNode::new(segment.clone(), data));
+                }
             }
         } else {
-            let mut next_node = *self.children.get(&segments[0]).expect("not such node");
-            unsafe { next_node.as_mut().insert(&segments[1..], data) };
+            let next_node = self.children.entry(segment.clone()).or_insert_with(|| {
+                let path = data.get_path().ancestors().nth(rest.len());
+                let placeholder = MerkleEntry::placeholder_directory(path.unwrap_or(Path::new("")));
+                TreeNode::new(segment.clone(), placeholder)
+            });
+            next_node.insert(rest, data);
         }
 
         self.recompute_node();
     }
 
-    fn remove(&mut self, segments: &[K]) {
-        if segments.len() == 1 {
-            let child_ptr = self.children.remove(&segments[0]).expect("not such node");
-            // SAFETY: This ensures the Box will be properly dropped after this scope, deallocating the memory.
-            let _ = unsafe { Box::from_raw(child_ptr.as_ptr()) };
-            return;
+    /// Removes the node at `segments`. Returns `None` if there is no such node.
+    fn remove(&mut self, segments: &[K]) -> Option<Self> {
+        let (segment, rest) = segments.split_first()?;
+        let removed = if rest.is_empty() {
+            self.children.remove(segment)
+        } else {
+            self.children.get_mut(segment)?.remove(rest)
+        };
+
+        if removed.is_some() {
+            self.recompute_node();
         }
+        removed
+    }
 
-        let mut next_node = *self.children.get(&segments[0]).expect("not such node");
-        unsafe { next_node.as_mut().remove(&segments[1..]) };
-        self.recompute_node();
+    fn collect_index_nodes<'a>(
+        &'a self,
+        out: &mut Vec<IndexNode<&'a K, &'a MerkleEntry, &'a VersionVector>>,
+    ) {
+        out.push(IndexNode {
+            segment: &self.segment,
+            child_count: self.children.len(),
+            hash: self.hash,
+            last_modified: self.last_modified,
+            data: &self.data,
+            version: &self.version,
+        });
+        self.children()
+            .for_each(|(_, child)| child.collect_index_nodes(out));
     }
 
+    /// Rebuilds a subtree from pre-ordered index nodes, verifying the stored hash of every node.
+    fn from_index_nodes(
+        nodes: &mut impl Iterator<Item = IndexNode<K, MerkleEntry, VersionVector>>,
+    ) -> io::Result<Self> {
+        let node = nodes
+            .next()
+            .ok_or_else(|| invalid_data("index ended unexpectedly"))?;
+        let mut tree_node = TreeNode {
+            children: BTreeMap::new(),
+            segment: node.segment,
+            hash: node.hash,
+            last_modified: node.last_modified,
+            data: node.data,
+            version: node.version,
+        };
+        for _ in 0..node.child_count {
+            let child = Self::from_index_nodes(nodes)?;
+            tree_node.children.insert(child.segment.clone(), child);
+        }
+
+        if tree_node.compute_hash() != tree_node.hash
+            || tree_node.compute_last_modified() != tree_node.last_modified
+        {
+            return Err(invalid_data("index hashes do not match its entries"));
+        }
+        Ok(tree_node)
+    }
+
+    fn children(&self) -> impl Iterator<Item = (&K, &TreeNode<K>)> {
+        self.children.iter()
+    }
+
+    /// Compares two nodes at the same path, treating `self` as the old and `other` as the new state.
     fn find_difference<'a>(
         &'a self,
         other: &'a Self,
-    ) -> Option<(TreeNodeDiffResult, TreeNodeDiffResult)> {
+        path: &mut Vec<K>,
+        diff: &mut TreeNodeDiff<'a, K>,
+    ) {
         if self.hash == other.hash {
             // if hashes are the same, we have the same content
-            return None;
-        }
-
-        // if one of both has no children, we found a leaf
-        let a_empty = self.children.is_empty();
-        let b_empty = other.children.is_empty();
-        if a_empty && b_empty {
-            if self.last_modified > other.last_modified {
-                return Some((
-                    HashMap::from([(&self.hash, (self.last_modified, &self.data))]),
-                    HashMap::new(),
-                ));
+            return;
+        }
+
+        match (&self.data, &other.data) {
+            (MerkleEntry::File(_), MerkleEntry::File(_)) => diff.changes.push(Change::Modified {
+                path: path.clone(),
+                old: &self.data,
+                new: &other.data,
+            }),
+            (MerkleEntry::Directory(_), MerkleEntry::Directory(_)) => {
+                find_diff_in_children(self, other, path, diff)
+            }
+            _ => {
+                diff.changes.push(Change::TypeChanged {
+                    path: path.clone(),
+                    old: &self.data,
+                    new: &other.data,
+                });
+                // children of a replaced directory are gone, children of a new directory are new
+                for (segment, child) in self.children() {
+                    diff.deleted.push((child_path(path, segment), child));
+                }
+                for (segment, child) in other.children() {
+                    diff.added.push((child_path(path, segment), child));
+                }
+            }
+        }
+    }
+
+    /// Pushes the paths of the topmost descendants that are not in `seen`.
+    /// Subtrees in `failed` are skipped since their state is unknown. Pending files and files that
+    /// are being written are expected, not deleted.
+    fn collect_unseen(
+        &self,
+        path: &mut Vec<K>,
+        seen: &HashSet<Vec<K>>,
+        failed: &HashSet<Vec<K>>,
+        in_flight: &HashSet<PathBuf>,
+        out: &mut Vec<Vec<K>>,
+    ) {
+        for (segment, child) in self.children() {
+            path.push(segment.clone());
+            if failed.contains(path) || in_flight.contains(child.data.get_path()) {
+                // keep previous state
+            } else if seen.contains(path) || child.has_pending() {
+                child.collect_unseen(path, seen, failed, in_flight, out);
             } else {
-                return Some((
-                    HashMap::new(),
-                    HashMap::from([(&other.hash, (self.last_modified, &other.data))]),
-                ));
+                out.push(path.clone());
             }
+            path.pop();
         }
-        if a_empty {
-            return Some((
-                HashMap::new(),
-                other
-                    .children
-                    .values()
-                    .map(|value| {
-                        let node = unsafe { value.as_ref() };
-                        (&node.hash, (node.last_modified, &node.data))
-                    })
-                    .collect(),
-            ));
-        }
-        if b_empty {
-            return Some((
-                self.children
-                    .values()
-                    .map(|value| {
-                        let node = unsafe { value.as_ref() };
-                        (&node.hash, (node.last_modified, &node.data))
-                    })
-                    .collect(),
-                HashMap::new(),
-            ));
+    }
+
+    /// Pushes the nodes of `self` that are unchanged at the same path in `other` by their hash.
+    /// Empty directories are skipped since they all share the same hash.
+    fn collect_unchanged<'a>(
+        &'a self,
+        other: &Self,
+        path: &mut Vec<K>,
+        out: &mut HashMap<BHash, Vec<(Vec<K>, &'a Self)>>,
+    ) {
+        for (segment, child) in self.children() {
+            let Some(other_child) = other.children.get(segment) else {
+                continue;
+            };
+            path.push(segment.clone());
+            if other_child.hash == child.hash {
+                let mut unchanged = Vec::new();
+                child.collect_pre_order(path, &mut unchanged);
+                unchanged
+                    .into_iter()
+                    .filter(|(_, node)| !node.is_empty_directory())
+                    .for_each(|(path, node)| out.entry(node.hash).or_default().push((path, node)));
+            } else {
+                child.collect_unchanged(other_child, path, out);
+            }
+            path.pop();
         }
+    }
 
-        find_diff_in_children(&self.children, &other.children)
+    /// Pushes this node and its descendants as added in pre-order.
+    /// Subtrees with the same content as one of `copy_sources` are pushed as a single copy instead.
+    fn collect_added<'a>(
+        &'a self,
+        path: &mut Vec<K>,
+        copy_sources: &HashMap<BHash, Vec<(Vec<K>, &Self)>>,
+        out: &mut Vec<Change<K, &'a MerkleEntry>>,
+    ) {
+        let source = copy_sources.get(&self.hash).and_then(|candidates| {
+            candidates
+                .iter()
+                .find(|(_, source)| is_same_kind(&source.data, &self.data))
+        });
+        if let Some((from, _)) = source {
+            out.push(Change::Copied {
+                from: from.clone(),
+                to: path.clone(),
+                new: &self.data,
+            });
+            return;
+        }
+
+        out.push(Change::Added {
+            path: path.clone(),
+            new: &self.data,
+        });
+        for (segment, child) in self.children() {
+            path.push(segment.clone());
+            child.collect_added(path, copy_sources, out);
+            path.pop();
+        }
     }
-}
 
-impl<K: AsRef<[u8]>> Drop for TreeNode<K> {
-    fn drop(&mut self) {
-        for child_ptr in self.children.values() {
-            // SAFETY: This ensures the Box will be properly dropped after this scope, deallocating the memory.
-            let _ = unsafe { Box::from_raw(child_ptr.as_ptr()) };
+    /// Pushes this node and all its descendants in pre-order.
+    fn collect_pre_order<'a>(&'a self, path: &mut Vec<K>, out: &mut Vec<(Vec<K>, &'a Self)>) {
+        out.push((path.clone(), self));
+        for (segment, child) in self.children() {
+            path.push(segment.clone());
+            child.collect_pre_order(path, out);
+            path.pop();
         }
-        // Parent does not need to be dropped because it does not own the child.
-        // The parent's existence is managed by its own scope/lifetime.
+    }
+
+    /// Pushes this node and all its descendants in post-order, i.e. children before their parent.
+    fn collect_post_order<'a>(&'a self, path: &mut Vec<K>, out: &mut Vec<(Vec<K>, &'a Self)>) {
+        for (segment, child) in self.children() {
+            path.push(segment.clone());
+            child.collect_post_order(path, out);
+            path.pop();
+        }
+        out.push((path.clone(), self));
     }
 }
 
-fn find_diff_in_children<'a, K: Eq + Ord + Hash + Clone + AsRef<[u8]>>(
-    self_children: &'a BTreeMap<K, NonNull<TreeNode<K>>>,
-    other_children: &'a BTreeMap<K, NonNull<TreeNode<K>>>,
-) -> Option<(TreeNodeDiffResult<'a>, TreeNodeDiffResult<'a>)> {
-    let mut diff1 = HashMap::new();
-    let mut diff2 = HashMap::new();
-    let mut common = Vec::new(); // To store common keys for further processing
+fn child_path<K: Clone>(path: &[K], segment: &K) -> Vec<K> {
+    let mut path = path.to_vec();
+    path.push(segment.clone());
+    path
+}
 
-    let mut iter_self = self_children.iter();
-    let mut iter_other = other_children.iter();
+fn find_diff_in_children<'a, K: Eq + Ord + Hash + Clone + AsRef<[u8]>>(
+    self_node: &'a TreeNode<K>,
+    other_node: &'a TreeNode<K>,
+    path: &mut Vec<K>,
+    diff: &mut TreeNodeDiff<'a, K>,
+) {
+    let mut iter_self = self_node.children();
+    let mut iter_other = other_node.children();
 
     let mut next_self = iter_self.next();
     let mut next_other = iter_other.next();
 
-    while next_self.is_some() || next_other.is_some() {
+    loop {
         match (next_self, next_other) {
-            (Some((key_self, value_self)), Some((key_other, value_other))) => {
+            (Some((key_self, node_self)), Some((key_other, node_other))) => {
                 match key_self.cmp(key_other) {
                     std::cmp::Ordering::Less => {
                         // key_self is unique to self
-                        let node = unsafe { value_self.as_ref() };
-                        diff1.insert(&node.hash, (node.last_modified, &node.data));
+                        diff.deleted.push((child_path(path, key_self), node_self));
                         next_self = iter_self.next();
                     }
                     std::cmp::Ordering::Greater => {
                         // key_other is unique to other
-                        let node = unsafe { value_other.as_ref() };
-                        diff2.insert(&node.hash, (node.last_modified, &node.data));
+                        diff.added.push((child_path(path, key_other), node_other));
                         next_other = iter_other.next();
                     }
                     std::cmp::Ordering::Equal => {
                         // key is present in both maps, check for differences in values
-                        common.push((unsafe { value_self.as_ref() }, unsafe {
-                            value_other.as_ref()
-                        }));
+                        path.push(key_self.clone());
+                        node_self.find_difference(node_other, path, diff);
+                        path.pop();
                         next_self = iter_self.next();
                         next_other = iter_other.next();
                     }
                 }
             }
-            (Some((_, value_self)), None) => {
+            (Some((key_self, node_self)), None) => {
                 // Remaining keys in self
-                let node = unsafe { value_self.as_ref() };
-                diff1.insert(&node.hash, (node.last_modified, &node.data));
+                diff.deleted.push((child_path(path, key_self), node_self));
                 next_self = iter_self.next();
             }
-            (None, Some((_, value_other))) => {
+            (None, Some((key_other, node_other))) => {
                 // Remaining keys in other
-                let node = unsafe { value_other.as_ref() };
-                diff2.insert(&node.hash, (node.last_modified, &node.data));
+                diff.added.push((child_path(path, key_other), node_other));
                 next_other = iter_other.next();
             }
             (None, None) => break,
         }
     }
+}
 
-    // Process common keys to find differences
-    common
-        .iter()
-        .filter_map(|(child1, child2)| child1.find_difference(child2))
-        .for_each(|(child1, child2)| {
-            diff1.extend(child1);
-            diff2.extend(child2);
-        });
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Borrow,
+        fs,
+        path::{Path, PathBuf},
+        sync::mpsc::{channel, Receiver},
+    };
 
-    Some((diff1, diff2))
+    use bincode::Options;
+
+    use super::{IndexHeader, MerkleTree, ScanReport, INDEX_VERSION};
+    use crate::{
+        datastructures::change::Change,
+        error::SyncronError,
+        filesystem::{
+            data::{MerkleEntry, PreviousScan},
+            scan::{walk_directory, walk_subtree, ScanMessage},
+        },
+        test_util::{rescan, segments, TempDir, OPTIONS},
+    };
+
+    /// The changes as they are printed, sorted so expected changes are easy to write down.
+    fn describe_all<E: Borrow<MerkleEntry>>(changes: &[Change<String, E>]) -> Vec<String> {
+        let mut changes = changes.iter().map(Change::to_string).collect::<Vec<_>>();
+        changes.sort();
+        changes
+    }
+
+    /// Applies a full scan of `dir` to `tree` and describes the changes.
+    fn scan_changes(tree: &mut MerkleTree<String>, dir: &TempDir) -> Vec<String> {
+        let previous = PreviousScan::from_entries(tree.entries());
+        let receiver = walk_directory(dir.path().to_owned(), OPTIONS, previous);
+        let report = tree.apply_scan(dir.path(), receiver);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        describe_all(&report.changes)
+    }
+
+    /// Applies a scan that sends exactly `messages`.
+    fn apply_messages(
+        tree: &mut MerkleTree<String>,
+        dir: &TempDir,
+        messages: Vec<ScanMessage>,
+    ) -> ScanReport<String> {
+        let (sender, receiver): (_, Receiver<_>) = channel();
+        messages
+            .into_iter()
+            .for_each(|message| sender.send(message).unwrap());
+        drop(sender);
+        tree.apply_scan(dir.path(), receiver)
+    }
+
+    #[test]
+    fn scans_are_applied_in_place() {
+        let dir = TempDir::new();
+        dir.write("a", "a");
+        dir.write("dir/b", "b");
+        dir.write("same", "same");
+        let mut tree = dir.scan();
+        let same = segments("same");
+        let version = tree.version(&same).cloned();
+        let last_modified = tree.get_last_modified(&same);
+
+        dir.write("a", "changed");
+        fs::remove_file(dir.path().join("dir/b")).unwrap();
+        dir.write("c", "c");
+        assert_eq!(
+            scan_changes(&mut tree, &dir),
+            ["added file c", "deleted file dir/b", "modified file a"]
+        );
+        assert_eq!(tree.get_hash(&[]), dir.scan().get_hash(&[]));
+        assert_eq!(tree.version(&same), version.as_ref());
+        assert_eq!(tree.get_last_modified(&same), last_modified);
+
+        assert!(scan_changes(&mut tree, &dir).is_empty());
+    }
+
+    #[test]
+    fn scans_detect_moves() {
+        let dir = TempDir::new();
+        dir.write("dir/sub/file", "file");
+        dir.write("dir/other", "other");
+        dir.write("single", "single");
+        dir.write("keep/x", "x");
+        let mut tree = dir.scan();
+
+        fs::rename(dir.path().join("dir"), dir.path().join("renamed")).unwrap();
+        fs::rename(dir.path().join("single"), dir.path().join("keep/single")).unwrap();
+        assert_eq!(
+            scan_changes(&mut tree, &dir),
+            [
+                "moved directory dir -> renamed",
+                "moved file single -> keep/single"
+            ]
+        );
+        assert_eq!(tree.get_hash(&[]), dir.scan().get_hash(&[]));
+    }
+
+    #[test]
+    fn scans_keep_failed_and_pending_paths() {
+        let dir = TempDir::new();
+        dir.write("dir/a", "a");
+        dir.write("b", "b");
+        let mut tree = dir.scan();
+        let incoming = segments("incoming");
+        let pending =
+            MerkleEntry::pending_file(dir.path().join("incoming"), blake3::hash(b"incoming"), 8);
+        tree.insert(&incoming, pending);
+
+        // the content of dir is unknown, so dir/a must not count as deleted
+        let dir_path = dir.path().join("dir");
+        let report = apply_messages(
+            &mut tree,
+            &dir,
+            vec![
+                ScanMessage::Entry(dir.entry("dir")),
+                ScanMessage::Error(SyncronError::PermissionDenied(dir_path)),
+                ScanMessage::Entry(dir.entry("b")),
+                ScanMessage::Done,
+            ],
+        );
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.changes.is_empty(), "{:?}", report.changes);
+        assert!(tree.try_get(&segments("dir/a")).is_some());
+        assert!(tree.get(&incoming).is_pending());
+
+        // neither does anything else when the scan ended early
+        let report = apply_messages(&mut tree, &dir, vec![ScanMessage::Entry(dir.entry("b"))]);
+        assert!(matches!(report.errors[..], [SyncronError::ScanAborted(_)]));
+        assert!(report.changes.is_empty(), "{:?}", report.changes);
+        assert!(tree.try_get(&segments("dir/a")).is_some());
+    }
+
+    #[test]
+    fn subtree_scans_only_change_the_subtree() {
+        let dir = TempDir::new();
+        dir.write("dir/a", "a");
+        dir.write("dir/skipped/c", "c");
+        dir.write("b", "b");
+        let mut tree = dir.scan();
+        let b = *tree.get_hash(&segments("b"));
+
+        dir.write("dir/a", "changed");
+        dir.write("b", "changed");
+        fs::remove_file(dir.path().join("dir/skipped/c")).unwrap();
+        let skip = vec![PathBuf::from("dir/skipped")];
+        let previous = PreviousScan::from_entries(tree.entries());
+        let receiver = walk_subtree(
+            dir.path().to_owned(),
+            PathBuf::from("dir"),
+            skip.clone(),
+            OPTIONS,
+            previous,
+        );
+        let report = tree.apply_subtree_scan(dir.path(), Path::new("dir"), &skip, receiver);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(describe_all(&report.changes), ["modified file dir/a"]);
+        assert_eq!(*tree.get_hash(&segments("b")), b);
+        assert!(tree.try_get(&segments("dir/skipped/c")).is_some());
+    }
+
+    #[test]
+    fn files_and_directories_never_share_a_hash() {
+        let (with_file, with_directory) = (TempDir::new(), TempDir::new());
+        with_file.write("x", [1]);
+        fs::create_dir(with_directory.path().join("x")).unwrap();
+        let file = with_file.scan();
+        let directory = with_directory.scan();
+
+        let x = segments("x");
+        assert_ne!(file.get_hash(&x), directory.get_hash(&x));
+        let changes = file.find_difference(&directory);
+        assert!(
+            matches!(changes[..], [Change::TypeChanged { ref path, .. }] if *path == x),
+            "{changes:?}"
+        );
+    }
+
+    #[test]
+    fn loading_an_index_follows_a_moved_root() {
+        let dir = TempDir::new();
+        dir.write("old/sub/file", "content");
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        let root = MerkleEntry::from_path(old.clone(), None).unwrap();
+        let mut tree = MerkleTree::new(old.to_str().unwrap().to_string(), root);
+        rescan(&mut tree, &old);
+        let index = dir.path().join("index");
+        tree.save(&index).unwrap();
+
+        fs::rename(&old, &new).unwrap();
+        let mut loaded = MerkleTree::<String>::load(&index, &new).unwrap();
+        rescan(&mut loaded, &new);
+        assert_eq!(loaded.local_path(&[]), new);
+        assert!(loaded.directories().all(|(path, _)| path.starts_with(&new)));
+        assert!(loaded
+            .entries()
+            .all(|entry| entry.get_path().starts_with(&new)));
+        assert_eq!(loaded.get_hash(&[]), tree.get_hash(&[]));
+    }
+
+    #[test]
+    fn differences_detect_moves_copies_and_type_changes() {
+        let (old, new) = (TempDir::new(), TempDir::new());
+        for dir in [&old, &new] {
+            dir.write("unchanged", "unchanged");
+        }
+        old.write("dir/sub/file", "moved");
+        new.write("renamed/sub/file", "moved");
+        new.write("copy", "unchanged");
+        old.write("kind", "file");
+        new.write("kind/file", "file");
+
+        let (old, new) = (old.scan(), new.scan());
+        assert_eq!(
+            describe_all(&old.find_difference(&new)),
+            // the content of the new directory still has to be created
+            [
+                "added file kind/file",
+                "copied file unchanged -> copy",
+                "moved directory dir -> renamed",
+                "replaced file kind with a directory"
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_need_an_unchanged_source() {
+        let (old, new) = (TempDir::new(), TempDir::new());
+        old.write("a", "old");
+        new.write("a", "same");
+        new.write("b", "same");
+
+        // a changed itself, so b can't be copied from it
+        let (old, new) = (old.scan(), new.scan());
+        assert_eq!(
+            describe_all(&old.find_difference(&new)),
+            ["added file b", "modified file a"]
+        );
+    }
+
+    /// Rewrites the header of the index at `path` with `change`, keeping its nodes.
+    fn rewrite_header(path: &Path, change: impl FnOnce(&mut IndexHeader)) {
+        let bytes = fs::read(path).unwrap();
+        let options = bincode::DefaultOptions::new();
+        let mut nodes = &bytes[..];
+        let mut header: IndexHeader = options.deserialize_from(&mut nodes).unwrap();
+        change(&mut header);
+        let mut rewritten = options.serialize(&header).unwrap();
+        rewritten.extend_from_slice(nodes);
+        fs::write(path, rewritten).unwrap();
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let dir = TempDir::new();
+        dir.write("dir/a", "a");
+        dir.write("b", "b");
+        let mut tree = dir.scan();
+        dir.write("b", "changed");
+        rescan(&mut tree, dir.path());
+        let index = dir.path().join(".syncron/index");
+        tree.save(&index).unwrap();
+
+        let loaded = MerkleTree::<String>::load(&index, dir.path()).unwrap();
+        assert_eq!(loaded.replica(), tree.replica());
+        assert_eq!(loaded.get_hash(&[]), tree.get_hash(&[]));
+        assert!(tree.find_difference(&loaded).is_empty());
+        let b = segments("b");
+        assert_eq!(loaded.version(&b), tree.version(&b));
+        assert_eq!(loaded.get_last_modified(&[]), tree.get_last_modified(&[]));
+    }
+
+    #[test]
+    fn damaged_indexes_are_rejected() {
+        let dir = TempDir::new();
+        dir.write("dir/a", "a");
+        let tree = dir.scan();
+        let index = dir.path().join(".syncron/index");
+        let load = || MerkleTree::<String>::load(&index, dir.path()).map(|_| ());
+
+        tree.save(&index).unwrap();
+        rewrite_header(&index, |header| header.version = INDEX_VERSION - 1);
+        assert!(load().unwrap_err().to_string().contains("version"));
+
+        tree.save(&index).unwrap();
+        let mut bytes = fs::read(&index).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&index, bytes).unwrap();
+        assert!(load().unwrap_err().to_string().contains("checksum"));
+
+        tree.save(&index).unwrap();
+        rewrite_header(&index, |header| header.root_hash = blake3::hash(b"other"));
+        assert!(load().unwrap_err().to_string().contains("root hash"));
+
+        tree.save(&index).unwrap();
+        assert!(load().is_ok());
+    }
 }
@@ -1 +1,6 @@
+pub mod change;
+pub mod conflict;
+pub mod event;
+pub mod merge;
 pub mod merkle_tree;
+pub mod version_vector;
@@ -0,0 +1,94 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::{self, Display},
+    process,
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies a copy of the synced directory, e.g. one host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ReplicaId(u64);
+impl ReplicaId {
+    /// Creates an id that is unique with high probability. It is derived from the time and process,
+    /// so it does not depend on clocks being in sync.
+    pub fn generate() -> Self {
+        static GENERATED: AtomicU64 = AtomicU64::new(0);
+        let mut hasher = blake3::Hasher::new();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        hasher.update(&now.as_nanos().to_le_bytes());
+        hasher.update(&process::id().to_le_bytes());
+        hasher.update(
+            &GENERATED
+                .fetch_add(1, AtomicOrdering::Relaxed)
+                .to_le_bytes(),
+        );
+        let hash = hasher.finalize();
+        Self(u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap()))
+    }
+}
+
+impl Display for ReplicaId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// How two versions of the same file relate to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    /// The first version happened before the second, so the second one includes its changes.
+    Ancestor,
+    /// The first version includes the changes of the second.
+    Descendant,
+    /// Both versions contain changes the other does not know about.
+    Concurrent,
+}
+
+/// Tracks the modifications of a file per replica, so versions can be compared without timestamps.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector {
+    counters: BTreeMap<ReplicaId, u64>,
+}
+impl VersionVector {
+    pub fn get(&self, replica: ReplicaId) -> u64 {
+        self.counters.get(&replica).copied().unwrap_or(0)
+    }
+
+    /// Records a modification by `replica` at its local `counter`, which must be higher than any it used before.
+    pub fn record(&mut self, replica: ReplicaId, counter: u64) {
+        let current = self.counters.entry(replica).or_default();
+        *current = counter.max(*current);
+    }
+
+    /// Combines the modifications known to both versions, e.g. after syncing a file.
+    pub fn merge(&mut self, other: &Self) {
+        for (replica, counter) in &other.counters {
+            self.record(*replica, *counter);
+        }
+    }
+
+    pub fn compare(&self, other: &Self) -> Causality {
+        let mut ordering = Ordering::Equal;
+        let replicas = self.counters.keys().chain(other.counters.keys());
+        for replica in replicas {
+            match (ordering, self.get(*replica).cmp(&other.get(*replica))) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, next) => ordering = next,
+                (current, next) if current != next => return Causality::Concurrent,
+                _ => {}
+            }
+        }
+        match ordering {
+            Ordering::Equal => Causality::Equal,
+            Ordering::Less => Causality::Ancestor,
+            Ordering::Greater => Causality::Descendant,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum SyncronError {
+    /// The path disappeared between being listed and being read, e.g. because it was renamed or deleted during a scan.
+    Vanished(PathBuf),
+    PermissionDenied(PathBuf),
+    /// The path is neither a file nor a directory (e.g. a socket or a broken symlink), or its name is not UTF-8.
+    Unsupported(PathBuf),
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Errors of the directory walk that are not related to a single path.
+    Walk(jwalk::Error),
+    /// The scan of the directory stopped before it saw everything, so it can't tell what was deleted.
+    ScanAborted(PathBuf),
+    Ignore {
+        path: PathBuf,
+        source: ignore::Error,
+    },
+    /// A line of a configuration file is invalid, e.g. of the conflicts file.
+    Config {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    /// The file watcher itself failed, independent of a watched path.
+    Watcher(io::Error),
+    /// The content received for the path does not have the expected hash.
+    Corrupted(PathBuf),
+    /// A file was to be written without its content being available.
+    MissingContent(PathBuf),
+    /// A path received from another replica that is not a path below the synced directory.
+    InvalidPath(PathBuf),
+    /// Another replica failed to apply a change.
+    Remote(String),
+    /// Syncing with another replica failed as a whole, e.g. because it could not be reached.
+    Peer {
+        addr: String,
+        source: io::Error,
+    },
+}
+impl SyncronError {
+    /// Classifies an I/O error that happened while accessing `path`.
+    pub fn from_io(path: PathBuf, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::NotFound => Self::Vanished(path),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(path),
+            _ => Self::Io { path, source },
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Vanished(path)
+            | Self::PermissionDenied(path)
+            | Self::Unsupported(path)
+            | Self::Io { path, .. }
+            | Self::Ignore { path, .. }
+            | Self::Config { path, .. }
+            | Self::Corrupted(path)
+            | Self::MissingContent(path)
+            | Self::InvalidPath(path)
+            | Self::ScanAborted(path) => Some(path),
+            Self::Walk(err) => err.path(),
+            Self::Watcher(_) | Self::Remote(_) | Self::Peer { .. } => None,
+        }
+    }
+}
+impl From<jwalk::Error> for SyncronError {
+    fn from(err: jwalk::Error) -> Self {
+        match (err.path(), err.io_error()) {
+            (Some(path), Some(_)) => {
+                let path = path.to_owned();
+                Self::from_io(path, err.into_io_error().unwrap())
+            }
+            _ => Self::Walk(err),
+        }
+    }
+}
+impl Display for SyncronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vanished(path) => write!(f, "{path:?} vanished while it was being read"),
+            Self::PermissionDenied(path) => write!(f, "permission denied for {path:?}"),
+            Self::Unsupported(path) => write!(
+                f,
+                "{path:?} can't be synced, only files and directories with UTF-8 names can"
+            ),
+            Self::Io { path, source } => write!(f, "unable to read {path:?}: {source}"),
+            Self::Walk(err) => write!(f, "unable to walk directory: {err}"),
+            Self::ScanAborted(path) => write!(f, "scan of {path:?} stopped before it was complete"),
+            Self::Ignore { path, source } => write!(f, "invalid ignore file {path:?}: {source}"),
+            Self::Config {
+                path,
+                line,
+                message,
+            } => write!(f, "invalid line {line} in {path:?}: {message}"),
+            Self::Watcher(err) => write!(f, "file watcher failed: {err}"),
+            Self::Corrupted(path) => {
+                write!(f, "content received for {path:?} does not match its hash")
+            }
+            Self::MissingContent(path) => write!(f, "no content to write to {path:?}"),
+            Self::InvalidPath(path) => write!(f, "{path:?} is outside of the synced directory"),
+            Self::Remote(message) => write!(f, "remote failed: {message}"),
+            Self::Peer { addr, source } => write!(f, "unable to sync with {addr}: {source}"),
+        }
+    }
+}
+impl Error for SyncronError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { source, .. } | Self::Watcher(source) | Self::Peer { source, .. } => {
+                Some(source)
+            }
+            Self::Walk(err) => Some(err),
+            Self::Ignore { source, .. } => Some(source),
+            Self::Vanished(_)
+            | Self::PermissionDenied(_)
+            | Self::Unsupported(_)
+            | Self::Config { .. }
+            | Self::Corrupted(_)
+            | Self::MissingContent(_)
+            | Self::InvalidPath(_)
+            | Self::ScanAborted(_)
+            | Self::Remote(_) => None,
+        }
+    }
+}
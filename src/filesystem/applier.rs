@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+};
+
+use blake3::Hash as BHash;
+
+use super::{
+    own_writes::{Expected, OwnWrites},
+    STAGING_DIR, SYNCRON_DIR,
+};
+use crate::{datastructures::event::TreeEvent, error::SyncronError};
+
+/// Does the changes published by a [MerkleTree](crate::datastructures::merkle_tree::MerkleTree) on disk,
+/// so the directory catches up with the tree.
+///
+/// Files are written with content that was [staged](Self::stage) before, since the tree only knows their hash.
+/// The content is written to the staging directory first, see [stage_chunk], and then renamed into place,
+/// so a file is never seen with partial content. Every change is recorded in [OwnWrites], so it is not taken
+/// for a local change.
+pub struct Applier {
+    root: PathBuf,
+    staged: HashMap<BHash, PathBuf>,
+    own_writes: OwnWrites,
+}
+impl Applier {
+    /// An applier for the tree whose root is at `root`, which records its changes in `own_writes`.
+    pub fn new(root: PathBuf, own_writes: OwnWrites) -> Self {
+        Self {
+            root,
+            staged: HashMap::new(),
+            own_writes,
+        }
+    }
+
+    /// Takes the content that was staged for `hash` for the file that is created or updated with it next.
+    /// Waits until the content is on disk and checks that it has the hash. Returns its size.
+    pub fn stage(&mut self, hash: BHash) -> Result<u64, SyncronError> {
+        let staged = staged_file(&self.root, &hash);
+        match finish_staged(&staged) {
+            Ok((written, size)) if written == hash => {
+                self.staged.insert(hash, staged);
+                Ok(size)
+            }
+            result => {
+                let _ = fs::remove_file(&staged);
+                match result {
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                        Err(SyncronError::MissingContent(staged))
+                    }
+                    Err(err) => Err(SyncronError::from_io(staged, err)),
+                    Ok(_) => Err(SyncronError::Corrupted(staged)),
+                }
+            }
+        }
+    }
+
+    /// Applies every event that was published so far in order and reports the ones that failed.
+    pub fn apply_pending<K: AsRef<[u8]>>(
+        &mut self,
+        events: &Receiver<TreeEvent<K>>,
+    ) -> Vec<(TreeEvent<K>, SyncronError)> {
+        events
+            .try_iter()
+            .filter_map(|event| self.apply(&event).err().map(|err| (event, err)))
+            .collect()
+    }
+
+    /// Does `event` on disk. What is in the way of a new entry is replaced.
+    pub fn apply<K: AsRef<[u8]>>(&mut self, event: &TreeEvent<K>) -> Result<(), SyncronError> {
+        let writes = match event {
+            TreeEvent::CreateFile { path, hash } | TreeEvent::UpdateFile { path, hash } => {
+                vec![(self.local_path(path), Expected::File(*hash))]
+            }
+            TreeEvent::DeleteFile(path) | TreeEvent::DeleteDir(path) => {
+                vec![(self.local_path(path), Expected::Removed)]
+            }
+            // a new directory is not a change of the tree
+            TreeEvent::CreateDir(_) => Vec::new(),
+            TreeEvent::Move { from, to } => vec![
+                (self.local_path(from), Expected::Removed),
+                (self.local_path(to), Expected::Subtree),
+            ],
+            TreeEvent::Copy { to, .. } => vec![(self.local_path(to), Expected::Subtree)],
+        };
+        for (path, expected) in &writes {
+            self.own_writes.start(path.clone(), *expected);
+        }
+        let result = self.write(event);
+        for (path, _) in &writes {
+            self.own_writes.finish(path, result.is_ok());
+        }
+        result
+    }
+
+    fn write<K: AsRef<[u8]>>(&mut self, event: &TreeEvent<K>) -> Result<(), SyncronError> {
+        match event {
+            TreeEvent::CreateFile { path, hash } | TreeEvent::UpdateFile { path, hash } => {
+                let local = self.local_path(path);
+                let Some(staged) = self.staged.remove(hash) else {
+                    return Err(SyncronError::MissingContent(local));
+                };
+                materialize(&staged, &local)
+            }
+            TreeEvent::DeleteFile(path) | TreeEvent::DeleteDir(path) => {
+                remove_entry(&self.local_path(path))
+            }
+            TreeEvent::CreateDir(path) => {
+                let local = self.local_path(path);
+                if local.is_dir() {
+                    return Ok(());
+                }
+                remove_entry(&local)?;
+                fs::create_dir(&local).map_err(|err| SyncronError::from_io(local, err))
+            }
+            TreeEvent::Move { from, to } => {
+                let (from, to) = (self.local_path(from), self.local_path(to));
+                remove_entry(&to)?;
+                fs::rename(&from, &to).map_err(|err| SyncronError::from_io(from, err))
+            }
+            TreeEvent::Copy { from, to } => {
+                let (from, to) = (self.local_path(from), self.local_path(to));
+                remove_entry(&to)?;
+                copy_entry(&from, &to)
+            }
+        }
+    }
+
+    fn local_path<K: AsRef<[u8]>>(&self, segments: &[K]) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(
+            segments
+                .iter()
+                .map(|segment| String::from_utf8_lossy(segment.as_ref()).into_owned()),
+        );
+        path
+    }
+}
+
+/// Where the content of a file with `hash` is staged below the sync root `root`.
+/// The staging directory is on the same filesystem as the files, so staged files can be renamed into place.
+fn staged_file(root: &Path, hash: &BHash) -> PathBuf {
+    root.join(SYNCRON_DIR)
+        .join(STAGING_DIR)
+        .join(format!("{}.tmp", hash.to_hex()))
+}
+
+/// Appends what `write` writes to the content staged for a file with `hash` below the sync root `root`, which has
+/// to have `offset` bytes so far. Content is staged in chunks, so a file never has to be in memory as a whole.
+/// Returns the size of the staged content afterwards.
+pub fn stage_chunk(
+    root: &Path,
+    hash: &BHash,
+    offset: u64,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> Result<u64, SyncronError> {
+    let staged = staged_file(root, hash);
+    let result = (|| {
+        let mut file = if offset == 0 {
+            fs::create_dir_all(staged.parent().unwrap())?;
+            File::create(&staged)?
+        } else {
+            File::options().append(true).open(&staged)?
+        };
+        if file.metadata()?.len() != offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chunk does not continue the staged content",
+            ));
+        }
+        write(&mut file)?;
+        Ok(file.metadata()?.len())
+    })();
+    result.map_err(|err| SyncronError::from_io(staged, err))
+}
+
+/// Waits until the staged file at `path` is on disk. Returns the hash and size of what was written, read back from disk.
+fn finish_staged(path: &Path) -> io::Result<(BHash, u64)> {
+    let file = File::options().append(true).open(path)?;
+    file.sync_all()?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_mmap_rayon(path)?;
+    Ok((hasher.finalize(), file.metadata()?.len()))
+}
+
+/// Moves the `staged` file to `path` in one step. What is at `path` is replaced.
+fn materialize(staged: &Path, path: &Path) -> Result<(), SyncronError> {
+    let in_the_way = match path.is_dir() {
+        true => remove_entry(path),
+        false => Ok(()),
+    };
+    let result = in_the_way.and_then(|()| {
+        fs::rename(staged, path).map_err(|err| SyncronError::from_io(path.to_owned(), err))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(staged);
+    }
+    result
+}
+
+/// Removes the file or directory at `path`. Succeeds if there is nothing to remove.
+fn remove_entry(path: &Path) -> Result<(), SyncronError> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    };
+    result.map_err(|err| SyncronError::from_io(path.to_owned(), err))
+}
+
+/// Copies the file or directory at `from` with everything below it to `to`.
+fn copy_entry(from: &Path, to: &Path) -> Result<(), SyncronError> {
+    if !from.is_dir() {
+        return fs::copy(from, to)
+            .map(|_| ())
+            .map_err(|err| SyncronError::from_io(from.to_owned(), err));
+    }
+    fs::create_dir(to).map_err(|err| SyncronError::from_io(to.to_owned(), err))?;
+    let children = fs::read_dir(from).map_err(|err| SyncronError::from_io(from.to_owned(), err))?;
+    for child in children {
+        let child = child.map_err(|err| SyncronError::from_io(from.to_owned(), err))?;
+        copy_entry(&child.path(), &to.join(child.file_name()))?;
+    }
+    Ok(())
+}
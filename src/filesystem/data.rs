@@ -1,41 +1,90 @@
 use blake3::{Hash, OUT_LEN};
+use serde::{Deserialize, Serialize};
+
+use crate::error::SyncronError;
 use std::{
-    fs::File,
+    collections::HashMap,
+    fs::{self, Metadata},
     path::{Path, PathBuf},
     time::SystemTime,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MerkleFile {
     path: PathBuf,
     last_modified: u64,
     hash: Hash,
+    /// Fingerprint used to detect unchanged files without hashing them.
+    size: u64,
+    /// nanoseconds since the epoch, negative for files from before it
+    modified: i128,
+    inode: u64,
+    /// the file is not on disk yet, see [MerkleEntry::pending_file]
+    pending: bool,
 }
 impl MerkleFile {
-    fn from_path(path: PathBuf) -> Self {
-        let file = File::open(&path).expect("unable to open file");
-        let metadata = file.metadata().expect("unable to read metadata");
-        let last_modified = metadata
-            .modified()
-            .expect("unable to read last modified")
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    /// Reads a file from disk. If `previous` has the same size, modification time and inode, its hash is reused instead of hashing the file again.
+    fn from_path(
+        path: PathBuf,
+        metadata: &Metadata,
+        previous: Option<&MerkleFile>,
+    ) -> Result<Self, SyncronError> {
+        let modified = match metadata.modified() {
+            Ok(modified) => since_epoch(modified),
+            Err(err) => return Err(SyncronError::from_io(path, err)),
+        };
+        // files from before 1970 count as modified at the epoch
+        let last_modified = u64::try_from(modified / 1_000_000_000).unwrap_or(0);
+        let size = metadata.len();
+        let inode = inode(metadata);
 
-        // TODO: open and read file data only once. Possibly copy impl of update_mmap_rayon.
-        let mut hasher = blake3::Hasher::new();
-        hasher.update_mmap_rayon(&path).expect("unable to hash");
-        let hash = hasher.finalize();
+        let unchanged = previous.filter(|previous| {
+            previous.size == size && previous.modified == modified && previous.inode == inode
+        });
+        let hash = match unchanged {
+            Some(previous) => previous.hash,
+            None => {
+                // TODO: open and read file data only once. Possibly copy impl of update_mmap_rayon.
+                let mut hasher = blake3::Hasher::new();
+                if let Err(err) = hasher.update_mmap_rayon(&path) {
+                    return Err(SyncronError::from_io(path, err));
+                }
+                hasher.finalize()
+            }
+        };
 
-        Self {
+        Ok(Self {
             path,
             last_modified,
             hash,
-        }
+            size,
+            modified,
+            inode,
+            pending: false,
+        })
+    }
+}
+
+/// Nanoseconds between the epoch and `time`, which can be before it.
+fn since_epoch(time: SystemTime) -> i128 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i128,
+        Err(before) => -(before.duration().as_nanos() as i128),
     }
 }
 
-#[derive(Debug)]
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+/// File ids are not available on stable for other platforms, so only size and modification time are compared.
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Directory {
     path: PathBuf,
 }
@@ -45,21 +94,53 @@ impl Directory {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MerkleEntry {
     File(MerkleFile),
     Directory(Directory),
 }
 impl MerkleEntry {
-    pub fn from_path(path: PathBuf) -> Self {
-        if path.is_file() {
-            return Self::File(MerkleFile::from_path(path));
+    /// Reads the entry at `path`. Fails with [SyncronError::Vanished] if the path no longer exists, e.g. because it was renamed during a scan.
+    pub fn from_path(path: PathBuf, previous: Option<&MerkleFile>) -> Result<Self, SyncronError> {
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            // the link itself still exists, only its target doesn't
+            Err(_) if fs::symlink_metadata(&path).is_ok_and(|link| link.is_symlink()) => {
+                return Err(SyncronError::Unsupported(path))
+            }
+            Err(err) => return Err(SyncronError::from_io(path, err)),
+        };
+        if metadata.is_file() {
+            return MerkleFile::from_path(path, &metadata, previous).map(Self::File);
         }
-        if path.is_dir() {
-            return Self::Directory(Directory::from_path(path));
+        if metadata.is_dir() {
+            return Ok(Self::Directory(Directory::from_path(path)));
         }
-        // TODO: we sometimes get an error here when renaming a file
-        unimplemented!()
+        Err(SyncronError::Unsupported(path))
+    }
+
+    /// A directory entry for `path` that was not read from disk, e.g. for a parent that was not scanned yet.
+    pub fn placeholder_directory(path: &Path) -> Self {
+        Self::Directory(Directory::from_path(path.to_owned()))
+    }
+
+    /// A file entry for content with `hash` that is not written to `path` yet.
+    /// It is [pending](Self::is_pending) until it is read from disk.
+    pub fn pending_file(path: PathBuf, hash: Hash, size: u64) -> Self {
+        Self::File(MerkleFile {
+            path,
+            last_modified: 0,
+            hash,
+            size,
+            modified: 0,
+            inode: 0,
+            pending: true,
+        })
+    }
+
+    /// Whether the entry is a file that is still being written, see [pending_file](Self::pending_file).
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Self::File(file) if file.pending)
     }
 
     pub fn get_path(&self) -> &Path {
@@ -80,4 +161,42 @@ impl MerkleEntry {
             Self::Directory(_) => 0, // default value that will be recomputed in tree
         }
     }
+    pub fn get_size(&self) -> u64 {
+        match self {
+            Self::File(file) => file.size,
+            Self::Directory(_) => 0,
+        }
+    }
+
+    /// The same entry at another path, e.g. for an entry of another replica.
+    pub fn with_path(&self, path: PathBuf) -> Self {
+        match self {
+            Self::File(file) => Self::File(MerkleFile {
+                path,
+                ..file.clone()
+            }),
+            Self::Directory(_) => Self::Directory(Directory::from_path(path)),
+        }
+    }
+}
+
+/// Files of a previous scan by path. Used to skip hashing files that did not change since.
+#[derive(Debug, Default)]
+pub struct PreviousScan {
+    files: HashMap<PathBuf, MerkleFile>,
+}
+impl PreviousScan {
+    pub fn from_entries<'a>(entries: impl Iterator<Item = &'a MerkleEntry>) -> Self {
+        let files = entries
+            .filter_map(|entry| match entry {
+                MerkleEntry::File(file) => Some((file.path.clone(), file.clone())),
+                MerkleEntry::Directory(_) => None,
+            })
+            .collect();
+        Self { files }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&MerkleFile> {
+        self.files.get(path)
+    }
 }
@@ -1,2 +1,11 @@
+pub mod applier;
 pub mod data;
+pub mod own_writes;
 pub mod scan;
+#[cfg(target_os = "linux")]
+pub mod watcher;
+
+/// Directory in the sync root where syncron keeps its own state. It is never scanned or synced.
+pub const SYNCRON_DIR: &str = ".syncron";
+/// Directory in [SYNCRON_DIR] where incoming files are written before they are moved into place.
+pub const STAGING_DIR: &str = "staging";
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use blake3::Hash as BHash;
+
+use super::data::MerkleEntry;
+
+/// How long a finished write is remembered if nobody notices it, e.g. because the path is not watched.
+const EXPIRY: Duration = Duration::from_secs(60);
+
+/// What syncron leaves at a path it changes itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    /// a file with this content
+    File(BHash),
+    /// nothing
+    Removed,
+    /// anything, e.g. while a directory is copied here
+    Subtree,
+}
+
+#[derive(Debug)]
+struct OwnWrite {
+    expected: Expected,
+    /// `None` while the write is in flight
+    finished: Option<Instant>,
+}
+
+/// The changes syncron makes on disk itself, so scans and the file watcher don't take them for
+/// local changes and send them back to where they came from.
+///
+/// A change is recorded before it starts. Until it is finished, everything seen at its path is part of it.
+/// Afterwards only its expected result is, which is forgotten once it was seen. Clones share the record.
+#[derive(Debug, Clone, Default)]
+pub struct OwnWrites(Arc<Mutex<HashMap<PathBuf, OwnWrite>>>);
+impl OwnWrites {
+    pub fn start(&self, path: PathBuf, expected: Expected) {
+        let write = OwnWrite {
+            expected,
+            finished: None,
+        };
+        self.0.lock().unwrap().insert(path, write);
+    }
+
+    /// Marks the change of `path` as done. If it `succeeded`, its result is expected to be seen.
+    pub fn finish(&self, path: &Path, succeeded: bool) {
+        let mut writes = self.0.lock().unwrap();
+        match writes.get_mut(path) {
+            // what ends up below a subtree can't be told apart from later changes
+            Some(write) if succeeded && write.expected != Expected::Subtree => {
+                write.finished = Some(Instant::now())
+            }
+            _ => {
+                writes.remove(path);
+            }
+        }
+    }
+
+    /// Forgets the finished changes that were not seen in time. Called once before each scan or batch of events.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.0.lock().unwrap().retain(|_, write| {
+            write
+                .finished
+                .is_none_or(|finished| now.duration_since(finished) < EXPIRY)
+        });
+    }
+
+    /// The paths that are being changed right now. Whatever is below them is part of the change.
+    pub fn in_flight(&self) -> HashSet<PathBuf> {
+        let writes = self.0.lock().unwrap();
+        writes
+            .iter()
+            .filter(|(_, write)| write.finished.is_none())
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Whether `entry` found at `path` (`None` if there is nothing) was caused by syncron itself.
+    pub fn is_own(&self, path: &Path, entry: Option<&MerkleEntry>) -> bool {
+        let mut writes = self.0.lock().unwrap();
+        let in_subtree = path.ancestors().skip(1).any(|ancestor| {
+            matches!(writes.get(ancestor), Some(write) if write.expected == Expected::Subtree)
+        });
+        if in_subtree {
+            return true;
+        }
+        let Some(write) = writes.get(path) else {
+            return false;
+        };
+        let (expected, in_flight) = (write.expected, write.finished.is_none());
+        let is_expected = match (expected, entry) {
+            (Expected::File(hash), Some(entry @ MerkleEntry::File(_))) => entry.get_hash() == hash,
+            (Expected::Removed, None) | (Expected::Subtree, _) => true,
+            _ => false,
+        };
+        // a finished change is seen once, anything else there afterwards is a local change
+        if !in_flight {
+            writes.remove(path);
+        }
+        is_expected || in_flight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expected, OwnWrites};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn everything_is_own_while_in_flight() {
+        let dir = TempDir::new();
+        dir.write("file", "partial");
+        let path = dir.path().join("file");
+        let writes = OwnWrites::default();
+        writes.start(path.clone(), Expected::File(blake3::hash(b"complete")));
+
+        assert!(writes.is_own(&path, Some(&dir.entry("file"))));
+        assert!(writes.is_own(&path, None));
+        assert!(writes.in_flight().contains(&path));
+    }
+
+    #[test]
+    fn finished_writes_are_own_once() {
+        let dir = TempDir::new();
+        dir.write("file", "complete");
+        let path = dir.path().join("file");
+        let entry = dir.entry("file");
+        let writes = OwnWrites::default();
+        writes.start(path.clone(), Expected::File(blake3::hash(b"complete")));
+        writes.finish(&path, true);
+
+        assert!(writes.in_flight().is_empty());
+        assert!(writes.is_own(&path, Some(&entry)));
+        // seen once, so the same content afterwards was written by someone else
+        assert!(!writes.is_own(&path, Some(&entry)));
+    }
+
+    #[test]
+    fn other_results_are_local_changes() {
+        let dir = TempDir::new();
+        dir.write("file", "local");
+        let path = dir.path().join("file");
+        let entry = dir.entry("file");
+        let writes = OwnWrites::default();
+
+        writes.start(path.clone(), Expected::File(blake3::hash(b"complete")));
+        writes.finish(&path, true);
+        assert!(!writes.is_own(&path, Some(&entry)));
+
+        writes.start(path.clone(), Expected::Removed);
+        writes.finish(&path, true);
+        assert!(!writes.is_own(&path, Some(&entry)));
+        writes.start(path.clone(), Expected::Removed);
+        writes.finish(&path, true);
+        assert!(writes.is_own(&path, None));
+
+        // failed writes leave nothing to expect
+        writes.start(path.clone(), Expected::Removed);
+        writes.finish(&path, false);
+        assert!(!writes.is_own(&path, None));
+    }
+
+    #[test]
+    fn subtrees_cover_their_content_until_finished() {
+        let dir = TempDir::new();
+        dir.write("copy/nested/file", "content");
+        let copy = dir.path().join("copy");
+        let file = dir.path().join("copy/nested/file");
+        let writes = OwnWrites::default();
+        writes.start(copy.clone(), Expected::Subtree);
+
+        assert!(writes.is_own(&copy, Some(&dir.entry("copy"))));
+        assert!(writes.is_own(&file, Some(&dir.entry("copy/nested/file"))));
+        assert!(writes.is_own(&file, None));
+        assert!(!writes.is_own(&dir.path().join("other"), None));
+
+        writes.finish(&copy, true);
+        assert!(!writes.is_own(&file, Some(&dir.entry("copy/nested/file"))));
+    }
+}
@@ -1,84 +1,246 @@
 use std::{
+    fs, io,
     path::{Path, PathBuf},
-    sync::mpsc::{channel, Receiver},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
 };
 
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::gitignore::{gitconfig_excludes_path, Gitignore, GitignoreBuilder};
 use jwalk::WalkDirGeneric;
 
-use super::data::MerkleEntry;
+use super::{
+    data::{MerkleEntry, PreviousScan},
+    SYNCRON_DIR,
+};
+use crate::error::SyncronError;
+
+const GITIGNORE: &str = ".gitignore";
+const SYNCIGNORE: &str = ".syncignore";
+/// File in [SYNCRON_DIR] with the settings of a sync root, see [IgnoreOptions::load].
+const SETTINGS_FILE: &str = "settings";
+
+/// Which ignore files decide what is synced. See [should_retain_path] for how they interact.
+#[derive(Debug, Clone, Copy)]
+pub struct IgnoreOptions {
+    /// Respect .gitignore files and the global gitignore. Disable for sync roots where e.g. build output should be synced.
+    pub gitignore: bool,
+}
+impl Default for IgnoreOptions {
+    fn default() -> Self {
+        Self { gitignore: true }
+    }
+}
+impl IgnoreOptions {
+    /// The options of the sync root `root`, read from its [SETTINGS_FILE]. Without the file the defaults are used.
+    ///
+    /// Every line of the file is a `key = value` setting, e.g. `gitignore = false`. Empty lines and lines
+    /// starting with `#` are skipped. Invalid lines are reported, the valid settings of the file are still used.
+    pub fn load(root: &Path) -> (Self, Vec<SyncronError>) {
+        let mut options = Self::default();
+        let path = root.join(SYNCRON_DIR).join(SETTINGS_FILE);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return (options, Vec::new()),
+            Err(err) => return (options, vec![SyncronError::from_io(path, err)]),
+        };
+
+        let mut errors = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let setting = match line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+            {
+                Some(("gitignore", value)) => value
+                    .parse()
+                    .map(|gitignore| options.gitignore = gitignore)
+                    .map_err(|_| "gitignore is either true or false".to_string()),
+                Some((key, _)) => Err(format!("unknown setting {key:?}")),
+                None => Err("expected a setting as key = value".to_string()),
+            };
+            if let Err(message) = setting {
+                errors.push(SyncronError::Config {
+                    path: path.clone(),
+                    line: number + 1,
+                    message,
+                });
+            }
+        }
+        (options, errors)
+    }
+}
+
+/// What a scan sends, see [walk_directory].
+#[derive(Debug)]
+pub enum ScanMessage {
+    Entry(MerkleEntry),
+    Error(SyncronError),
+    /// Everything was sent. A scan that ends without it is incomplete, e.g. because the scanner panicked.
+    Done,
+}
+
+/// Scans `path` in the background and sends every non-ignored entry below it, followed by [ScanMessage::Done].
+/// Files whose metadata is unchanged in `previous` are not hashed again.
+///
+/// Paths that can't be read are reported as errors instead of aborting the scan.
+pub fn walk_directory(
+    path: PathBuf,
+    options: IgnoreOptions,
+    previous: PreviousScan,
+) -> Receiver<ScanMessage> {
+    walk_subtree(path, PathBuf::new(), Vec::new(), options, previous)
+}
 
-pub fn walk_directory(path: PathBuf) -> Receiver<MerkleEntry> {
+/// Like [walk_directory], but only scans `subtree` without the directories in `skip`, both relative to the sync `root`.
+///
+/// The ignore files of the directories between `root` and `subtree` apply as if all of `root` was scanned.
+pub fn walk_subtree(
+    root: PathBuf,
+    subtree: PathBuf,
+    skip: Vec<PathBuf>,
+    options: IgnoreOptions,
+    previous: PreviousScan,
+) -> Receiver<ScanMessage> {
     let (sender, receiver) = channel();
 
-    rayon::spawn(move || {
-        walk_dir(&path)
-            .into_iter()
+    // jwalk reads directories on the rayon pool, so iterating must not block one of its threads.
+    thread::spawn(move || {
+        let walk = match walk_dir(&root, &subtree, skip, options, sender.clone()).try_into_iter() {
+            Ok(walk) => walk,
+            Err(err) => {
+                let _ = sender.send(ScanMessage::Error(err.into()));
+                let _ = sender.send(ScanMessage::Done);
+                return;
+            }
+        };
+        walk
             // skip root
-            .skip(1)
-            .map(|file| file.expect("unable to read file"))
-            .for_each(|file| {
-                sender
-                    .send(MerkleEntry::from_path(file.path()))
-                    .expect("unable to send");
-            });
+            .filter(|file| !matches!(file, Ok(file) if file.depth == 0))
+            .map(|file| {
+                let file = file?;
+                MerkleEntry::from_path(file.path(), previous.get(&file.path()))
+            })
+            .map(|entry| match entry {
+                Ok(entry) => ScanMessage::Entry(entry),
+                Err(err) => ScanMessage::Error(err),
+            })
+            .chain([ScanMessage::Done])
+            // stop if nobody is interested in the scan any more
+            .try_for_each(|message| sender.send(message).map_err(drop))
+            .ok();
     });
     receiver
 }
 
-fn walk_dir(path: &Path) -> WalkDirGeneric<(JwalkState, ())> {
-    // Build global .gitignore
-    let gitignore_global = match GitignoreBuilder::new(path).build_global() {
-        (_, Some(_err)) => panic!("error building global .gitignore"),
-        (gitignore, None) if gitignore.is_empty() => None,
-        (gitignore, None) => Some(gitignore),
-    };
-
-    // Get .gitignore from parent dirs if there is a .git repo
-    let mut gitignore_files = Vec::new();
-    let is_in_git_repo = path
-        .ancestors()
-        .skip(1)
-        .inspect(|ancestor| add_ignore_if_exists(ancestor, &mut gitignore_files))
-        .any(|ancestor| ancestor.join(".git").is_dir());
-    if is_in_git_repo {
-        gitignore_files.reverse();
-    } else {
-        gitignore_files.clear()
-    }
-
-    let initial_state = JwalkState {
-        gitignore_global,
-        gitignore_files,
-        is_in_git_repo,
+/// Walks `subtree` of `root` without the entries that are ignored or in `skip`. Errors in ignore files are sent to `errors`.
+fn walk_dir(
+    root: &Path,
+    subtree: &Path,
+    skip: Vec<PathBuf>,
+    options: IgnoreOptions,
+    errors: Sender<ScanMessage>,
+) -> WalkDirGeneric<(JwalkState, ())> {
+    let report = |err| {
+        let _ = errors.send(ScanMessage::Error(err));
     };
+    let initial_state = JwalkState::for_subtree(root, subtree, options, &report);
 
-    WalkDirGeneric::<(JwalkState, ())>::new(path)
+    let skip = skip.iter().map(|path| root.join(path)).collect::<Vec<_>>();
+    let root = root.to_owned();
+    WalkDirGeneric::<(JwalkState, ())>::new(root.join(subtree))
         .root_read_dir_state(initial_state)
         .skip_hidden(false)
-        .process_read_dir(|_, path, read_dir_state, children| {
-            // When there is a new git repo all previous .gitignore are not relevant any more
-            if path.join(".git").is_dir() {
-                read_dir_state.gitignore_files.clear();
-                read_dir_state.is_in_git_repo = true;
+        .process_read_dir(move |_, path, read_dir_state, children| {
+            // Never scan our own state, which includes files that are being written to the staging directory
+            if path == root {
+                children.retain(|dir_entry_result| {
+                    dir_entry_result
+                        .as_ref()
+                        .map(|dir_entry| dir_entry.file_name() != SYNCRON_DIR)
+                        .unwrap_or(true)
+                });
             }
-            // Check current dir for ignore files
-            if read_dir_state.is_in_git_repo {
-                add_ignore_if_exists(path, &mut read_dir_state.gitignore_files);
+            if !skip.is_empty() {
+                children.retain(|dir_entry_result| {
+                    dir_entry_result
+                        .as_ref()
+                        .map(|dir_entry| !skip.contains(&dir_entry.path()))
+                        .unwrap_or(true)
+                });
             }
 
-            // Remove ignored files and directories
+            // names are synced as UTF-8, anything else can't be represented in the tree
+            children.retain(|dir_entry_result| match dir_entry_result {
+                Ok(dir_entry) if dir_entry.file_name().to_str().is_none() => {
+                    let _ = errors.send(ScanMessage::Error(SyncronError::Unsupported(
+                        dir_entry.path(),
+                    )));
+                    false
+                }
+                _ => true,
+            });
+
+            read_dir_state.enter_dir(path, &|err| {
+                let _ = errors.send(ScanMessage::Error(err));
+            });
+
+            // Remove ignored files and directories. Errors are kept so they are reported.
             children.retain(|dir_entry_result| {
                 dir_entry_result
                     .as_ref()
                     .map(|dir_entry| should_retain_path(dir_entry.path(), read_dir_state))
-                    .unwrap_or(false)
+                    .unwrap_or(true)
             });
         })
 }
 
+/// Checks whether `path` is excluded from syncing by the same rules [walk_directory] applies when scanning `root`.
+pub fn is_ignored(root: &Path, path: &Path, options: IgnoreOptions) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return true;
+    };
+    if relative.starts_with(SYNCRON_DIR) {
+        return true;
+    }
+
+    // errors in ignore files are reported by the scans
+    let report = |_| {};
+    let mut state = JwalkState::for_root(root, options, &report);
+    let mut dir = root.to_owned();
+    for component in relative.components() {
+        state.enter_dir(&dir, &report);
+        let child = dir.join(component);
+        if !should_retain_path(child.clone(), &mut state) {
+            return true;
+        }
+        dir = child;
+    }
+    false
+}
+
 /// Checks if the path should be walked further.
+///
+/// Ignore files take precedence in this order:
+///  1. `.syncignore` files, which apply in every directory. A deeper file overrides its parents,
+///     and `!pattern` includes a path even if a .gitignore file ignores it.
+///  2. `.gitignore` files, only inside a git repo and if enabled in [IgnoreOptions].
+///  3. The global gitignore, under the same conditions as `.gitignore` files.
+///
+/// As with git, paths below an ignored directory can't be included again since the directory is never walked.
 fn should_retain_path(path: PathBuf, read_dir_state: &mut JwalkState) -> bool {
+    let is_dir = path.is_dir();
+    let syncignore_match = read_dir_state
+        .syncignore_files
+        .iter()
+        .rev()
+        .map(|syncignore| syncignore.matched(&path, is_dir))
+        .find(|matched| !matched.is_none());
+    if let Some(matched) = syncignore_match {
+        return matched.is_whitelist();
+    }
     if !read_dir_state.is_in_git_repo {
         return true;
     }
@@ -89,7 +251,7 @@ fn should_retain_path(path: PathBuf, read_dir_state: &mut JwalkState) -> bool {
         .rev()
         .enumerate()
         .find_map(|(layer, glob)| {
-            if glob.matched(&path, path.is_dir()).is_ignore() {
+            if glob.matched(&path, is_dir).is_ignore() {
                 Some(layer)
             } else {
                 None
@@ -101,7 +263,7 @@ fn should_retain_path(path: PathBuf, read_dir_state: &mut JwalkState) -> bool {
         .rev()
         .enumerate()
         .find_map(|(layer, glob)| {
-            if glob.matched(&path, path.is_dir()).is_whitelist() {
+            if glob.matched(&path, is_dir).is_whitelist() {
                 Some(layer)
             } else {
                 None
@@ -110,7 +272,7 @@ fn should_retain_path(path: PathBuf, read_dir_state: &mut JwalkState) -> bool {
     let is_ignored_by_global = read_dir_state
         .gitignore_global
         .clone()
-        .is_some_and(|global| global.matched(&path, path.is_dir()).is_ignore());
+        .is_some_and(|global| global.matched(&path, is_dir).is_ignore());
 
     match (ignored_at_layer, whitelisted_at_layer) {
         // If the file is not ignored and not whitelisted the global config decides.
@@ -124,19 +286,27 @@ fn should_retain_path(path: PathBuf, read_dir_state: &mut JwalkState) -> bool {
     }
 }
 
-/// TODO: add .syncignore files (similar to .git)
-fn add_ignore_if_exists(path: &Path, gitignore_files: &mut Vec<Gitignore>) {
-    let gitignore_file = path.join(".gitignore");
-    let mut gitignore_builder = GitignoreBuilder::new(path);
-    if gitignore_file.is_file() {
-        if let Some(err) = gitignore_builder.add(gitignore_file) {
-            panic!("error adding .gitignore: {err}");
+/// Loads the ignore file `name` in directory `path`. Returns an empty matcher if there is none.
+///
+/// Invalid patterns are reported, the valid patterns of the file are still used.
+fn load_ignore_file(path: &Path, name: &str, report: &dyn Fn(SyncronError)) -> Gitignore {
+    let ignore_file = path.join(name);
+    let mut builder = GitignoreBuilder::new(path);
+    if ignore_file.is_file() {
+        if let Some(err) = builder.add(&ignore_file) {
+            report(SyncronError::Ignore {
+                path: ignore_file.clone(),
+                source: err,
+            });
         }
     }
-    let gitignore = gitignore_builder
-        .build()
-        .expect("unable to build gitignore patterns");
-    gitignore_files.push(gitignore);
+    builder.build().unwrap_or_else(|err| {
+        report(SyncronError::Ignore {
+            path: ignore_file,
+            source: err,
+        });
+        Gitignore::empty()
+    })
 }
 
 #[derive(Debug, Default, Clone)]
@@ -144,4 +314,107 @@ struct JwalkState {
     gitignore_global: Option<Gitignore>,
     gitignore_files: Vec<Gitignore>,
     is_in_git_repo: bool,
+    /// only the directories that have a .syncignore file
+    syncignore_files: Vec<Gitignore>,
+    options: IgnoreOptions,
+}
+impl JwalkState {
+    /// State for walking `path`, built from the global .gitignore and the .gitignore files of its ancestors.
+    /// .syncignore files outside of `path` are not used.
+    fn for_root(path: &Path, options: IgnoreOptions, report: &dyn Fn(SyncronError)) -> Self {
+        if !options.gitignore {
+            return Self {
+                options,
+                ..Self::default()
+            };
+        }
+
+        // Build global .gitignore
+        let gitignore_global = match GitignoreBuilder::new(path).build_global() {
+            (gitignore, Some(err)) => {
+                let path = gitconfig_excludes_path().unwrap_or_default();
+                report(SyncronError::Ignore { path, source: err });
+                Some(gitignore).filter(|gitignore| !gitignore.is_empty())
+            }
+            (gitignore, None) if gitignore.is_empty() => None,
+            (gitignore, None) => Some(gitignore),
+        };
+
+        // Get .gitignore from parent dirs if there is a .git repo
+        let mut gitignore_files = Vec::new();
+        let is_in_git_repo = path
+            .ancestors()
+            .skip(1)
+            .inspect(|ancestor| gitignore_files.push(load_ignore_file(ancestor, GITIGNORE, report)))
+            .any(|ancestor| ancestor.join(".git").is_dir());
+        if is_in_git_repo {
+            gitignore_files.reverse();
+        } else {
+            gitignore_files.clear()
+        }
+
+        Self {
+            gitignore_global,
+            gitignore_files,
+            is_in_git_repo,
+            syncignore_files: Vec::new(),
+            options,
+        }
+    }
+
+    /// State for walking `subtree` (relative to `root`), with the ignore files of every directory in between.
+    fn for_subtree(
+        root: &Path,
+        subtree: &Path,
+        options: IgnoreOptions,
+        report: &dyn Fn(SyncronError),
+    ) -> Self {
+        let mut state = Self::for_root(root, options, report);
+        let mut dir = root.to_owned();
+        for component in subtree.components() {
+            state.enter_dir(&dir, report);
+            dir.push(component);
+        }
+        state
+    }
+
+    /// Updates the state with the ignore files of directory `path` before its children are checked.
+    fn enter_dir(&mut self, path: &Path, report: &dyn Fn(SyncronError)) {
+        // When there is a new git repo all previous .gitignore are not relevant any more
+        if self.options.gitignore && path.join(".git").is_dir() {
+            self.gitignore_files.clear();
+            self.is_in_git_repo = true;
+        }
+        // Check current dir for ignore files
+        if self.is_in_git_repo {
+            let gitignore = load_ignore_file(path, GITIGNORE, report);
+            self.gitignore_files.push(gitignore);
+        }
+        let syncignore = load_ignore_file(path, SYNCIGNORE, report);
+        if !syncignore.is_empty() {
+            self.syncignore_files.push(syncignore);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IgnoreOptions, SETTINGS_FILE};
+    use crate::{filesystem::SYNCRON_DIR, test_util::TempDir};
+
+    #[test]
+    fn settings_file_disables_gitignore() {
+        let dir = TempDir::new();
+        assert!(IgnoreOptions::load(dir.path()).0.gitignore);
+
+        let settings = format!("{SYNCRON_DIR}/{SETTINGS_FILE}");
+        dir.write(
+            &settings,
+            "# build output is synced\ngitignore = false\ncolor = blue\n",
+        );
+        let (options, errors) = IgnoreOptions::load(dir.path());
+        assert!(!options.gitignore);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("line 3"), "{}", errors[0]);
+    }
 }
@@ -0,0 +1,413 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use super::{
+    data::MerkleEntry,
+    scan::{is_ignored, IgnoreOptions},
+};
+use crate::{
+    datastructures::{change::Change, merkle_tree::MerkleTree},
+    error::SyncronError,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// The kernel dropped events, so the state of these directories is unknown.
+    Overflow(Vec<PathBuf>),
+}
+impl WatchEvent {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Created(path) | Self::Modified(path) | Self::Deleted(path) => path,
+            Self::Renamed { to, .. } => to,
+            Self::Overflow(_) => Path::new(""),
+        }
+    }
+}
+
+/// Watches single directories (not recursively) with inotify.
+pub struct Watcher {
+    inotify: Inotify,
+    /// watched directories by watch descriptor id
+    watches: HashMap<i32, (WatchDescriptor, PathBuf)>,
+    watched: HashMap<PathBuf, i32>,
+    debouncer: Debouncer,
+    buffer: Vec<u8>,
+}
+impl Watcher {
+    /// Creates a watcher whose events are only reported after no further event happened for `delay`.
+    pub fn new(delay: Duration) -> Result<Self, SyncronError> {
+        let inotify = Inotify::init().map_err(SyncronError::Watcher)?;
+        Ok(Self {
+            inotify,
+            watches: HashMap::new(),
+            watched: HashMap::new(),
+            debouncer: Debouncer::new(delay),
+            buffer: vec![0; 64 * 1024],
+        })
+    }
+
+    /// Starts watching the direct children of `dir`. Does nothing if it is already watched.
+    pub fn watch(&mut self, dir: &Path) -> Result<(), SyncronError> {
+        if self.watched.contains_key(dir) {
+            return Ok(());
+        }
+        let mask = WatchMask::CREATE
+            | WatchMask::MODIFY
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ONLYDIR;
+        let wd = self
+            .inotify
+            .watches()
+            .add(dir, mask)
+            .map_err(|err| SyncronError::from_io(dir.to_owned(), err))?;
+        let id = wd.get_watch_descriptor_id();
+        self.watches.insert(id, (wd, dir.to_owned()));
+        self.watched.insert(dir.to_owned(), id);
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self, dir: &Path) {
+        if let Some((wd, _)) = self
+            .watched
+            .remove(dir)
+            .and_then(|id| self.watches.remove(&id))
+        {
+            // fails if the directory is already gone, in which case the kernel removed the watch
+            let _ = self.inotify.watches().remove(wd);
+        }
+    }
+
+    /// The directories that are currently watched.
+    pub fn watched(&self) -> impl Iterator<Item = &Path> {
+        self.watched.keys().map(PathBuf::as_path)
+    }
+
+    pub fn is_watched(&self, dir: &Path) -> bool {
+        self.watched.contains_key(dir)
+    }
+
+    /// Reads all pending events without blocking.
+    /// Returns the events for paths that had no further events for the debounce delay.
+    pub fn poll(&mut self) -> Result<Vec<WatchEvent>, SyncronError> {
+        let now = Instant::now();
+        let mut moves = HashMap::new();
+        loop {
+            let events = match self.inotify.read_events(&mut self.buffer) {
+                Ok(events) => events.map(|event| event.to_owned()).collect::<Vec<_>>(),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(SyncronError::Watcher(err)),
+            };
+
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    let dirs = self.watched.keys().cloned().collect();
+                    self.debouncer.push(WatchEvent::Overflow(dirs), now);
+                    continue;
+                }
+                let id = event.wd.get_watch_descriptor_id();
+                if event.mask.contains(EventMask::IGNORED) {
+                    // the watch was removed, e.g. because the directory was deleted
+                    if let Some((_, dir)) = self.watches.remove(&id) {
+                        self.watched.remove(&dir);
+                    }
+                    continue;
+                }
+                let (Some((_, dir)), Some(name)) = (self.watches.get(&id), &event.name) else {
+                    continue;
+                };
+                let path = dir.join(name);
+
+                let watch_event = if event.mask.contains(EventMask::CREATE) {
+                    WatchEvent::Created(path)
+                } else if event.mask.contains(EventMask::DELETE) {
+                    WatchEvent::Deleted(path)
+                } else if event.mask.contains(EventMask::MOVED_FROM) {
+                    moves.insert(event.cookie, path);
+                    continue;
+                } else if event.mask.contains(EventMask::MOVED_TO) {
+                    match moves.remove(&event.cookie) {
+                        Some(from) => WatchEvent::Renamed { from, to: path },
+                        // moved in from a directory that is not watched
+                        None => WatchEvent::Created(path),
+                    }
+                } else {
+                    WatchEvent::Modified(path)
+                };
+                self.debouncer.push(watch_event, now);
+            }
+        }
+        // moved out to a directory that is not watched
+        for (_, from) in moves {
+            self.debouncer.push(WatchEvent::Deleted(from), now);
+        }
+
+        Ok(self.debouncer.ready(now))
+    }
+}
+
+/// Combines events on the same path until the path is quiet for `delay`.
+struct Debouncer {
+    delay: Duration,
+    pending: HashMap<PathBuf, (WatchEvent, Instant)>,
+    overflow: Option<Vec<PathBuf>>,
+}
+impl Debouncer {
+    fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: HashMap::new(),
+            overflow: None,
+        }
+    }
+
+    fn push(&mut self, event: WatchEvent, now: Instant) {
+        if let WatchEvent::Overflow(dirs) = event {
+            // overflows are reported right away, the events before it are still valid
+            self.overflow.get_or_insert_with(Vec::new).extend(dirs);
+            return;
+        }
+
+        let event = match event {
+            // renaming a new file only created it somewhere else
+            WatchEvent::Renamed { from, to }
+                if matches!(self.pending.get(&from), Some((WatchEvent::Created(_), _))) =>
+            {
+                self.pending.remove(&from);
+                WatchEvent::Created(to)
+            }
+            event => event,
+        };
+        let path = event.path().to_owned();
+        let event = match (self.pending.remove(&path), event) {
+            (None, event) => event,
+            // created and deleted before anyone noticed
+            (Some((WatchEvent::Created(_), _)), WatchEvent::Deleted(_)) => return,
+            (Some((WatchEvent::Created(_), _)), WatchEvent::Modified(path)) => {
+                WatchEvent::Created(path)
+            }
+            (Some((WatchEvent::Deleted(_), _)), WatchEvent::Created(path)) => {
+                WatchEvent::Modified(path)
+            }
+            (Some((renamed @ WatchEvent::Renamed { .. }, _)), WatchEvent::Modified(_)) => renamed,
+            (Some((WatchEvent::Renamed { from, .. }, _)), WatchEvent::Deleted(_)) => {
+                WatchEvent::Deleted(from)
+            }
+            (Some(_), event) => event,
+        };
+        self.pending.insert(event.path().to_owned(), (event, now));
+    }
+
+    /// Takes all events that were quiet for the delay, parents before their children.
+    fn ready(&mut self, now: Instant) -> Vec<WatchEvent> {
+        let mut ready = self
+            .pending
+            .iter()
+            .filter(|(_, (_, last_event))| now.duration_since(*last_event) >= self.delay)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        ready.sort();
+
+        let mut events = ready
+            .into_iter()
+            .filter_map(|path| self.pending.remove(&path))
+            .map(|(event, _)| event)
+            .collect::<Vec<_>>();
+        if let Some(dirs) = self.overflow.take() {
+            events.push(WatchEvent::Overflow(dirs));
+        }
+        events
+    }
+}
+
+/// Result of [apply_events].
+#[derive(Debug)]
+pub struct WatchReport<K> {
+    /// changes that were applied to the tree
+    pub changes: Vec<Change<K>>,
+    pub errors: Vec<SyncronError>,
+    /// directories whose content is unknown and needs to be scanned
+    pub rescan: Vec<PathBuf>,
+}
+
+/// Applies watcher events for paths below `root` to `tree`.
+///
+/// The current state of every path is read from disk, so events that are outdated by now are harmless.
+/// Events caused by the [own_writes](MerkleTree::own_writes) of the tree are skipped.
+pub fn apply_events<K>(
+    tree: &mut MerkleTree<K>,
+    root: &Path,
+    events: Vec<WatchEvent>,
+    options: IgnoreOptions,
+) -> WatchReport<K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + for<'a> From<&'a str>,
+{
+    let mut report = WatchReport {
+        changes: Vec::new(),
+        errors: Vec::new(),
+        rescan: Vec::new(),
+    };
+    tree.own_writes().expire();
+    for event in events {
+        match event {
+            WatchEvent::Created(path) | WatchEvent::Modified(path) | WatchEvent::Deleted(path) => {
+                refresh_path(tree, root, &path, options, &mut report)
+            }
+            WatchEvent::Renamed { from, to } => {
+                refresh_path(tree, root, &from, options, &mut report);
+                refresh_path(tree, root, &to, options, &mut report);
+            }
+            WatchEvent::Overflow(dirs) => report.rescan.extend(dirs),
+        }
+    }
+    report
+}
+
+fn refresh_path<K>(
+    tree: &mut MerkleTree<K>,
+    root: &Path,
+    path: &Path,
+    options: IgnoreOptions,
+    report: &mut WatchReport<K>,
+) where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + for<'a> From<&'a str>,
+{
+    if is_ignored(root, path, options) {
+        return;
+    }
+    let entry = MerkleEntry::from_path(path.to_owned(), None);
+    // what is at the path, unless it can't be read
+    let observed = match &entry {
+        Ok(entry) => Some(Some(entry)),
+        Err(SyncronError::Vanished(_) | SyncronError::Unsupported(_)) => Some(None),
+        Err(_) => None,
+    };
+    if observed.is_some_and(|observed| tree.own_writes().is_own(path, observed)) {
+        return;
+    }
+    match entry {
+        Ok(entry @ MerkleEntry::Directory(_)) => {
+            report.changes.extend(tree.update_entry(root, entry));
+            // a directory might have been moved here with all its content
+            report.rescan.push(path.to_owned());
+        }
+        Ok(entry) => report.changes.extend(tree.update_entry(root, entry)),
+        Err(SyncronError::Vanished(_) | SyncronError::Unsupported(_)) => {
+            report.changes.extend(tree.remove_path(root, path))
+        }
+        Err(err) => report.errors.push(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use super::{Debouncer, WatchEvent};
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    fn path(name: &str) -> PathBuf {
+        PathBuf::from("/watched").join(name)
+    }
+
+    #[test]
+    fn events_wait_until_the_path_is_quiet() {
+        let mut debouncer = Debouncer::new(DELAY);
+        let start = Instant::now();
+        debouncer.push(WatchEvent::Modified(path("b")), start);
+        debouncer.push(WatchEvent::Modified(path("a")), start);
+        debouncer.push(WatchEvent::Modified(path("b")), start + DELAY / 2);
+
+        assert!(debouncer.ready(start + DELAY / 2).is_empty());
+        assert_eq!(
+            debouncer.ready(start + DELAY),
+            [WatchEvent::Modified(path("a"))]
+        );
+        assert!(debouncer.ready(start + DELAY).is_empty());
+        assert_eq!(
+            debouncer.ready(start + 2 * DELAY),
+            [WatchEvent::Modified(path("b"))]
+        );
+    }
+
+    #[test]
+    fn events_of_a_path_are_combined() {
+        let mut debouncer = Debouncer::new(DELAY);
+        let start = Instant::now();
+        let push = |debouncer: &mut Debouncer, event| debouncer.push(event, start);
+
+        // created and removed again
+        push(&mut debouncer, WatchEvent::Created(path("temp")));
+        push(&mut debouncer, WatchEvent::Modified(path("temp")));
+        push(&mut debouncer, WatchEvent::Deleted(path("temp")));
+        // replaced
+        push(&mut debouncer, WatchEvent::Deleted(path("replaced")));
+        push(&mut debouncer, WatchEvent::Created(path("replaced")));
+        // created under a temporary name and renamed into place
+        push(&mut debouncer, WatchEvent::Created(path("new.tmp")));
+        push(
+            &mut debouncer,
+            WatchEvent::Renamed {
+                from: path("new.tmp"),
+                to: path("new"),
+            },
+        );
+        // renamed and then removed at its new path
+        push(
+            &mut debouncer,
+            WatchEvent::Renamed {
+                from: path("old"),
+                to: path("moved"),
+            },
+        );
+        push(&mut debouncer, WatchEvent::Deleted(path("moved")));
+
+        assert_eq!(
+            debouncer.ready(start + DELAY),
+            [
+                WatchEvent::Created(path("new")),
+                WatchEvent::Deleted(path("old")),
+                WatchEvent::Modified(path("replaced")),
+            ]
+        );
+    }
+
+    #[test]
+    fn overflows_are_reported_right_away() {
+        let mut debouncer = Debouncer::new(DELAY);
+        let start = Instant::now();
+        debouncer.push(WatchEvent::Modified(path("a")), start);
+        debouncer.push(WatchEvent::Overflow(vec![path("")]), start);
+
+        assert_eq!(
+            debouncer.ready(start),
+            [WatchEvent::Overflow(vec![path("")])]
+        );
+        assert_eq!(
+            debouncer.ready(start + DELAY),
+            [WatchEvent::Modified(path("a"))]
+        );
+    }
+}
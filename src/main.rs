@@ -38,50 +38,345 @@
 //! Test whether to use rayon or tokio (and possibly io_uring for linux and IoRing for windows) to scan directories and build index.
 //! Test memmap2 vs async IO when syncing files. Requires locking files for safety.
 
-use std::{path::Path, thread::sleep, time::Duration};
+use std::{
+    env,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
 
-use datastructures::merkle_tree::MerkleTree;
-use filesystem::data::MerkleEntry;
+use datastructures::merkle_tree::{MerkleTree, ScanReport};
+use error::SyncronError;
+use filesystem::{
+    data::{MerkleEntry, PreviousScan},
+    SYNCRON_DIR,
+};
 
-use crate::filesystem::scan::walk_directory;
+use crate::{
+    cron::scan_schedule::{ScanIntervals, ScanScheduler},
+    datastructures::conflict::{ConflictResolver, ConflictStrategy},
+    filesystem::scan::{walk_directory, walk_subtree, IgnoreOptions},
+    sync::{
+        mesh::{jittered, Peer},
+        session,
+        transport::TcpTransport,
+    },
+};
+#[cfg(target_os = "linux")]
+use crate::{
+    cron::watch_set::WatchSetScheduler,
+    datastructures::change::Change,
+    filesystem::watcher::{apply_events, WatchReport, Watcher},
+};
 
+mod cron;
 mod datastructures;
+mod error;
 mod filesystem;
+mod sync;
+#[cfg(test)]
+mod test_util;
 
 const TEST_DIR: &str = "C:\\Dev\\Rust\\syncron";
+#[cfg(target_os = "linux")]
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Maximum number of directories that are watched at the same time.
+#[cfg(target_os = "linux")]
+const WATCH_BUDGET: usize = 1024;
+/// How often a peer compares its tree with the other peers.
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(10);
 
 fn main() {
-    let mut tree1 = compute_tree(TEST_DIR);
-    let mut tree2 = compute_tree(TEST_DIR);
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => watch(TEST_DIR),
+        ["serve", dir, "--listen", addr] => serve(dir, addr),
+        ["sync", dir, "--remote", addr] => sync(dir, addr),
+        ["peer", dir, "--listen", addr, "--peers", peers] => mesh(dir, addr, peers),
+        _ => {
+            eprintln!(
+                "usage: syncron [serve <dir> --listen <addr> | sync <dir> --remote <addr> \
+                 | peer <dir> --listen <addr> --peers <addr>,...]"
+            );
+            process::exit(2);
+        }
+    }
+}
+
+/// Loads the index of `path` and catches up with what changed while we were not running.
+/// Scans `path` if there is no usable index.
+fn open_tree(path: &str, options: IgnoreOptions) -> (MerkleTree<String>, PathBuf) {
+    let index_path = Path::new(path).join(SYNCRON_DIR).join("index");
+    let tree = match MerkleTree::load(&index_path, Path::new(path)) {
+        Ok(mut tree) => {
+            let ScanReport { changes, errors } = rescan(&mut tree, path, options);
+            print_errors(&errors);
+            if !changes.is_empty() {
+                tree.save(&index_path).expect("unable to save index");
+            }
+            tree
+        }
+        Err(err) => {
+            println!("Unable to load index ({err}), scanning {path}");
+            let tree = compute_tree(path, options);
+            tree.save(&index_path).expect("unable to save index");
+            tree
+        }
+    };
+    (tree, index_path)
+}
+
+/// Accepts replicas that sync with `path`, one at a time.
+fn serve(path: &str, addr: &str) {
+    let options = ignore_options(path);
+    let (tree, index_path) = open_tree(path, options);
+    let peer = Peer::new(path, tree, Vec::new(), resolver(path));
+    let listener = TcpListener::bind(addr).expect("unable to listen");
+    println!("Serving {path} on {}", listener.local_addr().unwrap());
+    thread::scope(|scope| {
+        scope.spawn(|| keep_up_to_date(path, options, peer.tree(), &index_path));
+        accept(&peer, &listener);
+    });
+}
+
+/// Serves `path` on `addr` and regularly syncs it with the replicas at `peers`, which do the same.
+fn mesh(path: &str, addr: &str, peers: &str) {
+    let options = ignore_options(path);
+    let (tree, index_path) = open_tree(path, options);
+    let peers = peers.split(',').map(str::to_string).collect();
+    let peer = Peer::new(path, tree, peers, resolver(path));
+    let listener = TcpListener::bind(addr).expect("unable to listen");
+    println!("Serving {path} on {}", listener.local_addr().unwrap());
+    thread::scope(|scope| {
+        scope.spawn(|| keep_up_to_date(path, options, peer.tree(), &index_path));
+        scope.spawn(|| accept(&peer, &listener));
+        loop {
+            sleep(jittered(GOSSIP_INTERVAL));
+            let report = peer.gossip();
+            print_errors(&report.errors);
+            for (addr, sync) in report.synced {
+                print_errors(&sync.errors);
+                println!(
+                    "Pushed {} and pulled {} changes, resolved {} conflicts with {addr}",
+                    sync.pushed, sync.pulled, sync.conflicts
+                );
+            }
+        }
+    });
+}
+
+fn accept(peer: &Peer, listener: &TcpListener) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => print_errors(&peer.serve(stream)),
+            Err(err) => println!("Error: unable to accept connection: {err}"),
+        }
+    }
+}
+
+/// Syncs `path` in both directions with the replica serving at `addr`.
+fn sync(path: &str, addr: &str) {
+    let (mut tree, index_path) = open_tree(path, ignore_options(path));
+    let bases = Path::new(path).join(SYNCRON_DIR).join("peers");
+    let report = TcpTransport::connect(addr).and_then(|mut transport| {
+        session::sync(&mut tree, &bases, &resolver(path), &mut transport)
+    });
+    tree.save(&index_path).expect("unable to save index");
+    match report {
+        Ok(report) => {
+            print_errors(&report.errors);
+            println!(
+                "Pushed {} and pulled {} changes, resolved {} conflicts with {addr}",
+                report.pushed, report.pulled, report.conflicts
+            );
+            if !report.converged {
+                println!("Error: {path} and {addr} are not in sync yet");
+                process::exit(1);
+            }
+        }
+        Err(err) => {
+            println!("Error: unable to sync with {addr}: {err}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Keeps the tree of `path` up to date with periodic scans and file watchers.
+fn watch(path: &str) {
+    let options = ignore_options(path);
+    let (tree, index_path) = open_tree(path, options);
+    keep_up_to_date(path, options, &Mutex::new(tree), &index_path);
+}
+
+/// Keeps `tree` of `path` up to date with periodic scans and file watchers and saves it to `index_path` when
+/// they found changes. The tree is only locked while it is updated, so it can be synced in the meantime.
+fn keep_up_to_date(
+    path: &str,
+    options: IgnoreOptions,
+    tree: &Mutex<MerkleTree<String>>,
+    index_path: &Path,
+) {
+    #[cfg(target_os = "linux")]
+    let mut watcher = Watcher::new(WATCH_DEBOUNCE).expect("unable to start file watcher");
+    #[cfg(target_os = "linux")]
+    let watch_set = WatchSetScheduler::new(WATCH_BUDGET);
+
+    let mut scan_schedule = ScanScheduler::new(ScanIntervals::default());
+    // the root hash the schedule was last updated for
+    let mut scheduled = {
+        let tree = tree.lock().unwrap();
+        scan_schedule.update(&tree);
+        #[cfg(target_os = "linux")]
+        print_errors(&watch_set.update(&tree, &mut watcher).errors);
+        *tree.get_hash(&[])
+    };
 
     loop {
-        let diff = tree1.find_difference(&tree2);
-        if let Some((diff1, diff2)) = diff {
-            println!("Changed locally: {diff1:?}, changed remote: {diff2:?}")
+        let mut tree = tree.lock().unwrap();
+        let mut changes = Vec::new();
+        let now = Instant::now();
+        let due = scan_schedule
+            .due(now)
+            .into_iter()
+            .map(|(path, scan)| (path.to_owned(), scan.skip.clone()))
+            .collect::<Vec<_>>();
+        for (dir, skip) in due {
+            let ScanReport {
+                changes: scanned,
+                errors,
+            } = rescan_subtree(&mut tree, path, options, &dir, &skip);
+            print_errors(&errors);
+            changes.extend(scanned);
+            scan_schedule.mark_scanned(&dir, now);
+        }
+        #[cfg(target_os = "linux")]
+        changes.extend(apply_watch_events(&mut watcher, &mut tree, path, options));
+
+        // syncs change the tree as well
+        if !changes.is_empty() || *tree.get_hash(&[]) != scheduled {
+            // activity shifted, reschedule scans and follow the directories the user is working in
+            scan_schedule.update(&tree);
+            #[cfg(target_os = "linux")]
+            print_errors(&watch_set.update(&tree, &mut watcher).errors);
+            scheduled = *tree.get_hash(&[]);
+        }
+
+        for change in &changes {
+            println!("{change}");
+        }
+        if !changes.is_empty() {
+            if let Err(err) = tree.save(index_path) {
+                println!("Error: unable to save index: {err}");
+            }
         }
 
-        sleep(Duration::from_millis(100)); // TODO: merge changes into first tree instead of computing second tree and then switching
-        tree2 = compute_tree(TEST_DIR);
-        std::mem::swap(&mut tree1, &mut tree2);
+        drop(tree);
+        sleep(Duration::from_millis(100));
     }
 }
 
-fn compute_tree(path: &str) -> MerkleTree<String> {
+/// Resolves conflicts as configured in the conflicts file of `path`, keeping both versions by default.
+fn resolver(path: &str) -> ConflictResolver {
+    let (resolver, errors) = ConflictResolver::load(Path::new(path), ConflictStrategy::KeepBoth);
+    print_errors(&errors);
+    resolver
+}
+
+/// What is ignored in `path`, as configured in its settings file.
+fn ignore_options(path: &str) -> IgnoreOptions {
+    let (options, errors) = IgnoreOptions::load(Path::new(path));
+    print_errors(&errors);
+    options
+}
+
+fn compute_tree(path: &str, options: IgnoreOptions) -> MerkleTree<String> {
     let mut tree = MerkleTree::<String>::new(
         path.to_string(),
-        MerkleEntry::from_path(Path::new(&path).to_owned()),
+        MerkleEntry::from_path(Path::new(&path).to_owned(), None).expect("unable to read root"),
     );
+    print_errors(&rescan(&mut tree, path, options).errors);
+    tree
+}
 
-    let receiver = walk_directory(Path::new(&path).to_owned());
+/// Scans `path` and merges the result into `tree`. Unchanged files are not hashed again.
+fn rescan(tree: &mut MerkleTree<String>, path: &str, options: IgnoreOptions) -> ScanReport<String> {
+    let previous = PreviousScan::from_entries(tree.entries());
+    let receiver = walk_directory(Path::new(&path).to_owned(), options, previous);
+    tree.apply_scan(Path::new(&path), receiver)
+}
 
-    while let Ok(message) = receiver.recv() {
-        let path = message.get_path().strip_prefix(path).expect("invalid path");
-        let path_components = path
-            .components()
-            .map(|comp| comp.as_os_str().to_str().unwrap().to_owned())
-            .collect::<Vec<String>>();
+/// Scans only `dir` below `path` without the directories in `skip` and merges the result into `tree`.
+fn rescan_subtree(
+    tree: &mut MerkleTree<String>,
+    path: &str,
+    options: IgnoreOptions,
+    dir: &Path,
+    skip: &[PathBuf],
+) -> ScanReport<String> {
+    let root = Path::new(path);
+    let subtree = dir.strip_prefix(root).expect("directory outside of root");
+    let skip = skip
+        .iter()
+        .filter_map(|skipped| skipped.strip_prefix(root).ok())
+        .map(Path::to_owned)
+        .collect::<Vec<_>>();
+    let previous = PreviousScan::from_entries(
+        tree.entries()
+            .filter(|entry| entry.get_path().starts_with(dir)),
+    );
+    let receiver = walk_subtree(
+        root.to_owned(),
+        subtree.to_owned(),
+        skip.clone(),
+        options,
+        previous,
+    );
+    tree.apply_subtree_scan(root, subtree, &skip, receiver)
+}
 
-        tree.insert(&path_components, message);
+/// Applies the events of the file watcher to `tree`. Rescans the directories whose content the watcher can't tell.
+#[cfg(target_os = "linux")]
+fn apply_watch_events(
+    watcher: &mut Watcher,
+    tree: &mut MerkleTree<String>,
+    path: &str,
+    options: IgnoreOptions,
+) -> Vec<Change<String>> {
+    let events = match watcher.poll() {
+        Ok(events) => events,
+        Err(err) => {
+            print_errors(&[err]);
+            return Vec::new();
+        }
+    };
+    let WatchReport {
+        mut changes,
+        errors,
+        rescan: mut rescan_dirs,
+    } = apply_events(tree, Path::new(path), events, options);
+    print_errors(&errors);
+
+    // directories below another one are scanned with it
+    rescan_dirs.sort();
+    rescan_dirs.dedup_by(|dir, parent| dir.starts_with(parent));
+    for dir in rescan_dirs {
+        let report = rescan_subtree(tree, path, options, &dir, &[]);
+        print_errors(&report.errors);
+        changes.extend(report.changes);
+    }
+    changes
+}
+
+fn print_errors(errors: &[SyncronError]) {
+    for err in errors {
+        println!("Error: {err}");
     }
-    tree
 }
@@ -0,0 +1,211 @@
+use std::{
+    hash::Hash,
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use blake3::Hash as BHash;
+
+use super::{
+    delta::map_file,
+    protocol::{Chunk, Operation},
+};
+use crate::{
+    datastructures::{
+        merkle_tree::{InsertError, MerkleTree},
+        version_vector::VersionVector,
+    },
+    error::SyncronError,
+    filesystem::{
+        applier::{stage_chunk, Applier},
+        data::MerkleEntry,
+        SYNCRON_DIR,
+    },
+};
+
+/// Applies `operation` to `tree` and then does it on the directory of `tree` with an [Applier].
+///
+/// Paths of the operation are checked to stay below the synced directory, since they come from another host.
+/// If the directory can't be changed, the tree is put back to how it was, so the next sync tries again.
+pub fn apply_operation<K>(
+    tree: &mut MerkleTree<K>,
+    operation: Operation<K>,
+) -> Result<(), SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let paths = match &operation {
+        Operation::Remove(path)
+        | Operation::CreateDirectory(path)
+        | Operation::WriteFile { path, .. }
+        | Operation::MergeVersion { path, .. }
+        | Operation::Copy { to: path, .. } => vec![path.clone()],
+        Operation::Move { from, to } => vec![from.clone(), to.clone()],
+    };
+    let written = matches!(operation, Operation::WriteFile { .. });
+    let snapshots = paths
+        .iter()
+        .map(|path| tree.snapshot(path))
+        .collect::<Vec<_>>();
+
+    let mut applier = Applier::new(tree.local_path(&[]), tree.own_writes().clone());
+    let events = tree.subscribe();
+    let result = update_tree(tree, &mut applier, operation);
+    tree.unsubscribe();
+    result?;
+    if let Some((_, err)) = applier.apply_pending(&events).into_iter().next() {
+        for (path, snapshot) in paths.iter().zip(snapshots).rev() {
+            tree.restore(path, snapshot);
+        }
+        return Err(err);
+    }
+
+    if written {
+        // the metadata of the file is only known once it is written
+        let entry = MerkleEntry::from_path(tree.local_path(&paths[0]), None)?;
+        tree.insert_synced(&paths[0], entry, &VersionVector::default())
+            .map_err(|err| insert_error(tree, err))?;
+    }
+    Ok(())
+}
+
+/// Checks `operation` and applies it to `tree`, which publishes what has to be done on disk.
+/// Content of written files is staged in `applier`.
+fn update_tree<K>(
+    tree: &mut MerkleTree<K>,
+    applier: &mut Applier,
+    operation: Operation<K>,
+) -> Result<(), SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    match operation {
+        Operation::Remove(path) => {
+            checked_path(tree, &path)?;
+            tree.try_remove(&path);
+        }
+        Operation::CreateDirectory(path) => {
+            let local = checked_path(tree, &path)?;
+            tree.try_insert(&path, MerkleEntry::placeholder_directory(&local))
+                .map_err(|err| insert_error(tree, err))?;
+        }
+        Operation::WriteFile {
+            path,
+            hash,
+            version,
+        } => write_file(tree, applier, &path, hash, &version)?,
+        Operation::Copy { from, to, hash } => {
+            let local_from = checked_path(tree, &from)?;
+            let local_to = checked_path(tree, &to)?;
+            if tree.try_get(&from).is_some() && *tree.get_hash(&from) != hash {
+                // the source changed here, so the copy would not have the content it should
+                return Err(SyncronError::Corrupted(local_to));
+            }
+            if !tree
+                .try_copy(&from, &to)
+                .map_err(|err| insert_error(tree, err))?
+            {
+                return Err(SyncronError::Vanished(local_from));
+            }
+        }
+        Operation::Move { from, to } => {
+            let local_from = checked_path(tree, &from)?;
+            checked_path(tree, &to)?;
+            if !tree
+                .try_move(&from, &to)
+                .map_err(|err| insert_error(tree, err))?
+            {
+                return Err(SyncronError::Vanished(local_from));
+            }
+        }
+        Operation::MergeVersion { path, version } => {
+            let local = checked_path(tree, &path)?;
+            let entry = match tree.try_get(&path) {
+                Some(entry @ MerkleEntry::File(_)) => entry.clone(),
+                _ => return Err(SyncronError::Vanished(local)),
+            };
+            tree.insert_synced(&path, entry, &version)
+                .map_err(|err| insert_error(tree, err))?;
+        }
+    }
+    Ok(())
+}
+
+/// Inserts the file with the content staged for `hash` at `path` into `tree`. The content is checked against `hash` before.
+fn write_file<K>(
+    tree: &mut MerkleTree<K>,
+    applier: &mut Applier,
+    path: &[K],
+    hash: BHash,
+    version: &VersionVector,
+) -> Result<(), SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let local = checked_path(tree, path)?;
+    let size = applier.stage(hash)?;
+    let entry = MerkleEntry::pending_file(local, hash, size);
+    tree.insert_synced(path, entry, version)
+        .map_err(|err| insert_error(tree, err))
+}
+
+/// Appends `chunk` to the content staged for a file with `hash` in the directory of `tree`, see [stage_chunk].
+/// A delta is applied to the file of `tree` it was made against.
+pub fn stage<K>(
+    tree: &MerkleTree<K>,
+    hash: &BHash,
+    offset: u64,
+    chunk: &Chunk<K>,
+) -> Result<u64, SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let root = tree.local_path(&[]);
+    match chunk {
+        Chunk::Data(data) => stage_chunk(&root, hash, offset, |file| file.write_all(data)),
+        Chunk::Delta { base, delta } => {
+            let local = checked_path(tree, base)?;
+            let base = map_file(&local).map_err(|err| SyncronError::from_io(local, err))?;
+            stage_chunk(&root, hash, offset, |file| delta.apply(&base, file))
+        }
+    }
+}
+
+/// The path of `segments` on disk if every segment is a plain name and it is not our own state.
+pub fn checked_path<K>(tree: &MerkleTree<K>, segments: &[K]) -> Result<PathBuf, SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let path = tree.local_path(segments);
+    let is_valid = segments
+        .first()
+        .is_some_and(|first| first.as_ref() != SYNCRON_DIR.as_bytes())
+        && segments.iter().all(|segment| {
+            let segment = String::from_utf8_lossy(segment.as_ref());
+            let mut components = Path::new(segment.as_ref()).components();
+            matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(name)), None) if name == segment.as_ref()
+            )
+        });
+    if is_valid {
+        Ok(path)
+    } else {
+        Err(SyncronError::InvalidPath(path))
+    }
+}
+
+/// A failed insert means the other replica did not know about an entry in the way, e.g. a file where it expects a directory.
+fn insert_error<K>(tree: &MerkleTree<K>, err: InsertError<K>) -> SyncronError
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let path = match err {
+        InsertError::EmptyPath => tree.local_path(&[]),
+        InsertError::NotADirectory(path) => tree.local_path(&path),
+    };
+    SyncronError::from_io(
+        path,
+        io::Error::new(io::ErrorKind::NotADirectory, "not a directory in the index"),
+    )
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use blake3::Hash as BHash;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use super::protocol::CHUNK_SIZE;
+
+/// Smaller files are always sent whole, a delta would not save much.
+pub const MIN_DELTA_SIZE: u64 = 64 * 1024;
+const MIN_BLOCK_SIZE: usize = 1024;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+/// Blocks of huge files are larger than [MAX_BLOCK_SIZE], so their signature fits into one message.
+const MAX_BLOCKS: usize = 1 << 20;
+/// What an instruction of a [Delta] is counted as when it is split into parts, besides the data it inserts.
+const INSTRUCTION_SIZE: usize = 24;
+
+/// The blocks of the version of a file the receiver has, so the sender can send only what is different.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    block_size: u32,
+    blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockSignature {
+    /// cheap to compute at every offset, see [Rolling]
+    weak: u32,
+    strong: BHash,
+}
+
+impl Signature {
+    /// Splits `base` into blocks. Blocks grow with the file, so large files don't have too many of them.
+    pub fn new(base: &[u8]) -> Self {
+        let block_size = ((base.len() as f64).sqrt() as usize)
+            .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+            .max(base.len().div_ceil(MAX_BLOCKS));
+        let blocks = base
+            .chunks(block_size)
+            .map(|block| BlockSignature {
+                weak: Rolling::new(block).digest(),
+                strong: blake3::hash(block),
+            })
+            .collect();
+        Self {
+            block_size: block_size as u32,
+            blocks,
+        }
+    }
+}
+
+/// How to build (a part of) a file out of the blocks of the version the receiver has and new data, see [BlockMatcher].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delta {
+    block_size: u32,
+    instructions: Vec<Instruction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Instruction {
+    /// `count` blocks of the base, starting with block `first`
+    Copy {
+        first: u64,
+        count: u64,
+    },
+    Insert(Vec<u8>),
+}
+
+/// A [Signature] prepared to find its blocks in other data.
+pub struct BlockMatcher {
+    signature: Signature,
+    /// blocks by their weak checksum
+    blocks: HashMap<u32, Vec<usize>>,
+}
+impl BlockMatcher {
+    pub fn new(signature: Signature) -> Self {
+        let mut blocks = HashMap::<u32, Vec<usize>>::new();
+        for (i, block) in signature.blocks.iter().enumerate() {
+            blocks.entry(block.weak).or_default().push(i);
+        }
+        Self { signature, blocks }
+    }
+
+    /// The part of the delta that builds `data` from the file the signature was made of, starting at `offset` of `data`.
+    /// A part carries about [CHUNK_SIZE] bytes at most and ends at the returned offset. At the end of `data` it is empty.
+    ///
+    /// Blocks of the base are found at any offset of `data`, so inserting or removing data only sends what was inserted.
+    pub fn delta(&self, data: &[u8], offset: usize) -> (Delta, usize) {
+        let block_size = self.signature.block_size as usize;
+        let mut delta = Delta {
+            block_size: self.signature.block_size,
+            instructions: Vec::new(),
+        };
+        let mut start = offset.min(data.len());
+        if block_size == 0 || self.blocks.is_empty() {
+            let end = start.saturating_add(CHUNK_SIZE).min(data.len());
+            delta.insert(&data[start..end]);
+            return (delta, end);
+        }
+
+        let window_end = |start: usize| (start + block_size).min(data.len());
+        let mut literal = start;
+        let mut inserted = 0;
+        let mut rolling = Rolling::new(&data[start..window_end(start)]);
+        while start < data.len()
+            && inserted + (start - literal) + delta.instructions.len() * INSTRUCTION_SIZE
+                < CHUNK_SIZE
+        {
+            let end = window_end(start);
+            let found = self.blocks.get(&rolling.digest()).and_then(|candidates| {
+                let strong = blake3::hash(&data[start..end]);
+                candidates
+                    .iter()
+                    .find(|&&i| self.signature.blocks[i].strong == strong)
+            });
+            match found {
+                Some(&block) => {
+                    inserted += start - literal;
+                    delta.insert(&data[literal..start]);
+                    delta.copy(block as u64);
+                    start = end;
+                    literal = end;
+                    rolling = Rolling::new(&data[start..window_end(start)]);
+                }
+                None => {
+                    rolling.roll(data[start], data.get(end).copied());
+                    start += 1;
+                }
+            }
+        }
+        delta.insert(&data[literal..start]);
+        (delta, start)
+    }
+}
+
+impl Delta {
+    /// Whether the delta builds nothing, e.g. because it is the part after the end of the data.
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    fn insert(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.instructions.push(Instruction::Insert(data.to_vec()));
+        }
+    }
+
+    fn copy(&mut self, block: u64) {
+        match self.instructions.last_mut() {
+            Some(Instruction::Copy { first, count }) if *first + *count == block => *count += 1,
+            _ => self.instructions.push(Instruction::Copy {
+                first: block,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Writes what the delta builds out of `base`, which is expected to be the file the signature was made of.
+    /// Fails with [InvalidData](io::ErrorKind::InvalidData) if the delta refers to blocks `base` does not have.
+    pub fn apply(&self, base: &[u8], out: &mut impl Write) -> io::Result<()> {
+        let block_size = self.block_size as u64;
+        for instruction in &self.instructions {
+            match instruction {
+                Instruction::Copy { first, count } => {
+                    let blocks = (|| {
+                        let start = usize::try_from(first.checked_mul(block_size)?).ok()?;
+                        let end =
+                            usize::try_from(first.checked_add(*count)?.checked_mul(block_size)?)
+                                .ok()?
+                                .min(base.len());
+                        base.get(start..end)
+                    })();
+                    let Some(blocks) = blocks else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "delta refers to blocks the base does not have",
+                        ));
+                    };
+                    out.write_all(blocks)?;
+                }
+                Instruction::Insert(bytes) => out.write_all(bytes)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maps the file at `path` into memory, so signatures and deltas can be made of it without reading it as a whole.
+pub fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    // SAFETY: syncron never writes to files in place, it renames new versions over them. A file changed by
+    // someone else meanwhile makes the delta wrong, which the hash of the result catches.
+    unsafe { Mmap::map(&file) }
+}
+
+/// Checksum of a window that can be moved by one byte without reading the whole window again, as in rsync.
+struct Rolling {
+    /// sum of the bytes
+    a: u32,
+    /// sum of the bytes weighted by their distance to the end of the window
+    b: u32,
+    len: u32,
+}
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let mut rolling = Self {
+            a: 0,
+            b: 0,
+            len: window.len() as u32,
+        };
+        for (i, &byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((window.len() - i) as u32 * byte as u32);
+        }
+        rolling
+    }
+
+    /// Moves the window by one byte: `out` leaves it at the front and `next` enters it at the back.
+    /// Without `next` the window shrinks, e.g. at the end of the data.
+    fn roll(&mut self, out: u8, next: Option<u8>) {
+        self.a = self.a.wrapping_sub(out as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32));
+        match next {
+            Some(byte) => {
+                self.a = self.a.wrapping_add(byte as u32);
+                self.b = self.b.wrapping_add(self.a);
+            }
+            None => self.len -= 1,
+        }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockMatcher, Signature, CHUNK_SIZE};
+
+    /// Pseudo-random bytes, so blocks don't repeat.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    /// Builds `new` from `base` part by part. Returns the number of new bytes that were sent.
+    fn rebuild(base: &[u8], new: &[u8]) -> usize {
+        let matcher = BlockMatcher::new(Signature::new(base));
+        let mut built = Vec::new();
+        let mut sent = 0;
+        let mut offset = 0;
+        loop {
+            let (delta, end) = matcher.delta(new, offset);
+            let part = bincode::serialize(&delta).unwrap().len();
+            assert!(part <= CHUNK_SIZE + 64, "part of {part} bytes");
+            sent += part;
+            delta.apply(base, &mut built).unwrap();
+            assert_eq!(built.len(), end);
+            if delta.is_empty() {
+                break;
+            }
+            offset = end;
+        }
+        assert!(built == new);
+        sent
+    }
+
+    #[test]
+    fn parts_of_a_delta_rebuild_the_file() {
+        let base = data(2 * CHUNK_SIZE, 1);
+        let mut edited = base.clone();
+        edited.splice(100_000..100_000, *b"inserted");
+        edited.drain(1_500_000..1_500_100);
+        assert!(rebuild(&base, &edited) < 64 * 1024);
+
+        let rewritten = data(2 * CHUNK_SIZE + 17, 2);
+        assert!(rebuild(&base, &rewritten) > rewritten.len());
+        assert!(rebuild(&base, &[]) < 64);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+};
+
+use blake3::Hash as BHash;
+
+use super::{
+    protocol::{unexpected_response, Request, Response},
+    server::{handle_request, Connection},
+    session::{self, SyncReport},
+    transport::{serve_connection, TcpTransport, Transport},
+};
+use crate::{
+    datastructures::{
+        conflict::ConflictResolver, merkle_tree::MerkleTree, version_vector::ReplicaId,
+    },
+    error::SyncronError,
+    filesystem::SYNCRON_DIR,
+};
+
+/// A replica in a mesh of peers that sync with each other without a central server.
+///
+/// Every peer serves its tree and regularly gossips with the peers it knows. Changes it learned from one
+/// peer are relayed to the others, since they are changes since its last sync with them.
+///
+/// The peer does not scan its directory itself, the [tree](Self::tree) has to be kept up to date
+/// with scans and file watchers while the peer runs.
+pub struct Peer {
+    index_path: PathBuf,
+    bases: PathBuf,
+    tree: Mutex<MerkleTree<String>>,
+    /// locked while the peer syncs, whether it started the sync or serves it
+    syncing: Mutex<()>,
+    peers: Vec<String>,
+    resolver: ConflictResolver,
+    /// root hash of the state last kept as synced per replica, so it is not saved again
+    synced: Mutex<HashMap<ReplicaId, BHash>>,
+}
+
+/// Result of [Peer::gossip].
+#[derive(Debug, Default)]
+pub struct GossipReport {
+    /// the peers whose tree differed, with the result of the sync with them
+    pub synced: Vec<(String, SyncReport)>,
+    pub errors: Vec<SyncronError>,
+}
+
+impl Peer {
+    /// A peer for the sync root `root` with its current `tree`, which gossips with the replicas at `peers`.
+    pub fn new(
+        root: &str,
+        tree: MerkleTree<String>,
+        peers: Vec<String>,
+        resolver: ConflictResolver,
+    ) -> Self {
+        let state = Path::new(root).join(SYNCRON_DIR);
+        Self {
+            index_path: state.join("index"),
+            bases: state.join("peers"),
+            tree: Mutex::new(tree),
+            syncing: Mutex::new(()),
+            peers,
+            resolver,
+            synced: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The tree of the peer, which is locked while a sync uses it.
+    pub fn tree(&self) -> &Mutex<MerkleTree<String>> {
+        &self.tree
+    }
+
+    /// Answers the requests of a peer that syncs with us until it disconnects.
+    /// The index is only saved if the peer changed our tree.
+    ///
+    /// A peer that connects while we are syncing is refused instead of waiting, since we might be waiting for it.
+    pub fn serve(&self, stream: TcpStream) -> Vec<SyncronError> {
+        let addr = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+        let Ok(_syncing) = self.syncing.try_lock() else {
+            let result = serve_connection(stream, |_: Request<String>| {
+                Response::Failed("replica is busy syncing".to_string())
+            });
+            return result
+                .err()
+                .map(|source| SyncronError::Peer { addr, source })
+                .into_iter()
+                .collect();
+        };
+
+        let mut tree = self.tree.lock().unwrap();
+        let mut connection = Connection::default();
+        let mut changed = false;
+        let result = serve_connection(stream, |request| {
+            let is_operation = matches!(request, Request::Apply(_));
+            let response = handle_request(&mut tree, &self.bases, &mut connection, request);
+            changed |= is_operation && matches!(response, Response::Applied);
+            response
+        });
+        let mut errors = Vec::new();
+        if changed {
+            if let Err(err) = tree.save(&self.index_path) {
+                errors.push(SyncronError::from_io(self.index_path.clone(), err));
+            }
+        }
+        errors.extend(
+            result
+                .err()
+                .map(|source| SyncronError::Peer { addr, source }),
+        );
+        errors
+    }
+
+    /// Compares the root hash with every known peer and syncs with the peers whose tree differs.
+    pub fn gossip(&self) -> GossipReport {
+        let _syncing = self.syncing.lock().unwrap();
+        let mut tree = self.tree.lock().unwrap();
+        let mut report = GossipReport::default();
+        for addr in &self.peers {
+            match self.gossip_with(&mut tree, addr) {
+                Ok(Some(sync)) => report.synced.push((addr.clone(), sync)),
+                Ok(None) => {}
+                Err(source) => report.errors.push(SyncronError::Peer {
+                    addr: addr.clone(),
+                    source,
+                }),
+            }
+        }
+        if report.synced.iter().any(|(_, sync)| sync.pulled > 0) {
+            if let Err(err) = tree.save(&self.index_path) {
+                report
+                    .errors
+                    .push(SyncronError::from_io(self.index_path.clone(), err));
+            }
+        }
+        report
+    }
+
+    /// Syncs with the peer at `addr` if its root hash differs from ours.
+    fn gossip_with(
+        &self,
+        tree: &mut MutexGuard<MerkleTree<String>>,
+        addr: &str,
+    ) -> io::Result<Option<SyncReport>> {
+        let mut transport = TcpTransport::connect(addr)?;
+        let (replica, hash) = match transport.request(Request::<String>::Root)? {
+            Response::Root { replica, hash } => (replica, hash),
+            Response::Failed(message) => return Err(io::Error::other(message)),
+            _ => return Err(unexpected_response()),
+        };
+        let mut synced = self.synced.lock().unwrap();
+        if hash == *tree.get_hash(&[]) {
+            // both sides got to the same state through other peers
+            if synced.get(&replica) != Some(&hash) {
+                session::converged(tree, &self.bases, replica, &mut transport)?;
+                synced.insert(replica, hash);
+            }
+            return Ok(None);
+        }
+        let report = session::sync(tree, &self.bases, &self.resolver, &mut transport)?;
+        if report.converged {
+            synced.insert(replica, *tree.get_hash(&[]));
+        }
+        Ok(Some(report))
+    }
+}
+
+/// Varies `interval` by up to half of it, so peers that started together do not keep syncing at the same time
+/// and refusing each other.
+pub fn jittered(interval: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let factor = 0.5 + f64::from(nanos % 1000) / 1000.0;
+    interval.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::{TcpListener, TcpStream},
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::Peer;
+    use crate::{
+        datastructures::conflict::{ConflictResolver, ConflictStrategy},
+        test_util::TempDir,
+    };
+
+    #[test]
+    fn three_peers_converge() {
+        let dirs = [TempDir::new(), TempDir::new(), TempDir::new()];
+        for (i, dir) in dirs.iter().enumerate() {
+            dir.write(&format!("peer{i}/file"), format!("written by peer {i}"));
+        }
+        dirs[0].write("shared", "edited by peer 0");
+        let listeners = dirs
+            .each_ref()
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap());
+        let addrs = listeners
+            .each_ref()
+            .map(|listener| listener.local_addr().unwrap().to_string());
+        let peers = [0, 1, 2].map(|i| {
+            let mut others = addrs.to_vec();
+            others.remove(i);
+            let resolver = ConflictResolver::new(ConflictStrategy::KeepBoth);
+            Peer::new(dirs[i].root(), dirs[i].scan(), others, resolver)
+        });
+
+        let stopped = AtomicBool::new(false);
+        let mut errors = Vec::new();
+        thread::scope(|scope| {
+            for (peer, listener) in peers.iter().zip(&listeners) {
+                let stopped = &stopped;
+                scope.spawn(move || {
+                    for stream in listener.incoming() {
+                        if stopped.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        peer.serve(stream.unwrap());
+                    }
+                });
+            }
+            for _ in 0..2 {
+                for peer in &peers {
+                    errors.extend(peer.gossip().errors);
+                }
+            }
+            stopped.store(true, Ordering::Relaxed);
+            for addr in &addrs {
+                TcpStream::connect(addr).unwrap();
+            }
+        });
+        assert!(errors.is_empty(), "{errors:?}");
+
+        let hashes = peers
+            .each_ref()
+            .map(|peer| *peer.tree().lock().unwrap().get_hash(&[]));
+        assert!(hashes.iter().all(|hash| *hash == hashes[0]));
+        for dir in &dirs {
+            for i in 0..3 {
+                let content = fs::read_to_string(dir.path().join(format!("peer{i}/file")));
+                assert_eq!(content.unwrap(), format!("written by peer {i}"));
+            }
+            let shared = fs::read_to_string(dir.path().join("shared")).unwrap();
+            assert_eq!(shared, "edited by peer 0");
+        }
+    }
+}
@@ -0,0 +1,9 @@
+pub mod apply;
+pub mod delta;
+pub mod mesh;
+pub mod plan;
+pub mod protocol;
+pub mod reconcile;
+pub mod server;
+pub mod session;
+pub mod transport;
@@ -0,0 +1,273 @@
+use std::hash::Hash;
+
+use blake3::Hash as BHash;
+
+use super::protocol::Operation;
+use crate::{
+    datastructures::{
+        change::Change,
+        conflict::{Resolution, Side},
+        merkle_tree::MerkleTree,
+        version_vector::VersionVector,
+    },
+    filesystem::data::MerkleEntry,
+};
+
+/// Something to do on one of the replicas.
+#[derive(Debug)]
+pub enum Step<K> {
+    Send(Operation<K>),
+    /// Writes the file at `from` of the other replica to `to`. Files are only read when they are sent.
+    Transfer {
+        from: Vec<K>,
+        to: Vec<K>,
+        hash: BHash,
+        version: VersionVector,
+    },
+}
+
+/// Orders the steps to apply `changes` of `source` to the other replica, so every step finds the state it expects.
+/// `changed` are the paths the other replica changed itself since the last sync.
+///
+/// Everything that is removed is removed first. What is created afterwards is created in path order,
+/// so parents exist before their children. Moves are done while creating, so their source must not be
+/// below a removed path. Those are transferred instead, as are copies whose source the other replica changed,
+/// since it has different content there.
+pub fn plan<K>(
+    source: &MerkleTree<K>,
+    changes: &[Change<K, &MerkleEntry>],
+    changed: &[&[K]],
+) -> Vec<Step<K>>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let mut removed = changes
+        .iter()
+        .filter_map(|change| match change {
+            Change::Deleted { path, .. } | Change::TypeChanged { path, .. } => {
+                Some(path.as_slice())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    // everything below a removed path is removed with it
+    removed.sort();
+    removed.dedup_by(|path, parent| path.starts_with(parent));
+
+    let mut created = Vec::new();
+    for change in changes {
+        match change {
+            Change::Added { path, new }
+            | Change::Modified { path, new, .. }
+            | Change::TypeChanged { path, new, .. } => {
+                created.push((path.clone(), create(source, path, new)))
+            }
+            Change::Copied { from, to, .. } => {
+                if changed
+                    .iter()
+                    .any(|path| path.starts_with(from) || from.starts_with(path))
+                {
+                    create_subtree(source, &mut to.clone(), &mut created);
+                } else {
+                    let operation = Operation::Copy {
+                        from: from.clone(),
+                        to: to.clone(),
+                        hash: *source.get_hash(to),
+                    };
+                    created.push((to.clone(), Step::Send(operation)));
+                }
+            }
+            Change::Moved { from, to, .. } => {
+                if removed.iter().any(|path| from.starts_with(path)) {
+                    create_subtree(source, &mut to.clone(), &mut created);
+                } else {
+                    let operation = Operation::Move {
+                        from: from.clone(),
+                        to: to.clone(),
+                    };
+                    created.push((to.clone(), Step::Send(operation)));
+                }
+            }
+            Change::Deleted { .. } => {}
+        }
+    }
+    created.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    removed
+        .into_iter()
+        .map(|path| Step::Send(Operation::Remove(path.to_vec())))
+        .chain(created.into_iter().map(|(_, step)| step))
+        .collect()
+}
+
+/// The steps for `resolution` on the remote and on the local replica.
+///
+/// The remote steps run first and read from the local replica before it is changed. The local steps
+/// run afterwards, so they read a conflict copy from where the remote steps moved it.
+/// The file that ends up at the path has the modifications of both sides in its version.
+pub fn plan_resolution<K>(
+    local: &MerkleTree<K>,
+    remote: &MerkleTree<K>,
+    resolution: &Resolution<K>,
+) -> (Vec<Step<K>>, Vec<Step<K>>)
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let path = &resolution.path;
+    let mut version = local.version(path).cloned().unwrap_or_default();
+    version.merge(&remote.version(path).cloned().unwrap_or_default());
+
+    let (winner, loser) = match resolution.winner {
+        Side::Local => (local, remote),
+        Side::Remote => (remote, local),
+    };
+    let mut winner_steps = Vec::new();
+    let mut loser_steps = Vec::new();
+    let replace = replace(winner, path, &version);
+    if matches!(winner.try_get(path), Some(MerkleEntry::File(_))) {
+        winner_steps.push(Step::Send(Operation::MergeVersion {
+            path: path.clone(),
+            version,
+        }));
+    }
+    match (&resolution.copy, resolution.winner) {
+        (None, _) => loser_steps.push(replace),
+        (Some(copy), Side::Local) => {
+            // the remote keeps its version under the copy path, which is read from there afterwards
+            loser_steps.push(Step::Send(Operation::Move {
+                from: path.clone(),
+                to: copy.clone(),
+            }));
+            loser_steps.push(replace);
+            winner_steps.push(Step::Transfer {
+                from: copy.clone(),
+                to: copy.clone(),
+                hash: *loser.get_hash(path),
+                version: loser.version(path).cloned().unwrap_or_default(),
+            });
+        }
+        (Some(copy), Side::Remote) => {
+            // the remote gets the local version before the local one is moved out of the way
+            winner_steps.push(transfer(loser, path, copy.clone()));
+            loser_steps.push(Step::Send(Operation::Move {
+                from: path.clone(),
+                to: copy.clone(),
+            }));
+            loser_steps.push(replace);
+        }
+    }
+
+    match resolution.winner {
+        Side::Local => (loser_steps, winner_steps),
+        Side::Remote => (winner_steps, loser_steps),
+    }
+}
+
+/// The step that replaces the entry at `path` with the one of `source`.
+fn replace<K>(source: &MerkleTree<K>, path: &[K], version: &VersionVector) -> Step<K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    match source.try_get(path) {
+        Some(MerkleEntry::File(_)) => Step::Transfer {
+            from: path.to_vec(),
+            to: path.to_vec(),
+            hash: *source.get_hash(path),
+            version: version.clone(),
+        },
+        Some(MerkleEntry::Directory(_)) => Step::Send(Operation::CreateDirectory(path.to_vec())),
+        None => Step::Send(Operation::Remove(path.to_vec())),
+    }
+}
+
+fn transfer<K>(source: &MerkleTree<K>, from: &[K], to: Vec<K>) -> Step<K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    Step::Transfer {
+        from: from.to_vec(),
+        to,
+        hash: *source.get_hash(from),
+        version: source.version(from).cloned().unwrap_or_default(),
+    }
+}
+
+fn create<K>(source: &MerkleTree<K>, path: &[K], entry: &MerkleEntry) -> Step<K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    match entry {
+        MerkleEntry::File(_) => transfer(source, path, path.to_vec()),
+        MerkleEntry::Directory(_) => Step::Send(Operation::CreateDirectory(path.to_vec())),
+    }
+}
+
+/// Pushes the steps to create the node at `path` of `source` and everything below it.
+fn create_subtree<K>(source: &MerkleTree<K>, path: &mut Vec<K>, out: &mut Vec<(Vec<K>, Step<K>)>)
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    out.push((path.clone(), create(source, path, source.get(path))));
+    let children = source
+        .children(path)
+        .into_iter()
+        .flatten()
+        .map(|(segment, _, _)| segment.clone())
+        .collect::<Vec<_>>();
+    for segment in children {
+        path.push(segment);
+        create_subtree(source, path, out);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan, Step};
+    use crate::{
+        datastructures::version_vector::ReplicaId,
+        sync::protocol::Operation,
+        test_util::{rescan, segments, TempDir},
+    };
+
+    /// Copies `a.txt` to `c.txt` locally, while the remote changes `a.txt` if `remote_changes_source`.
+    /// Returns the steps that bring the copy to the remote.
+    fn push_copy(remote_changes_source: bool) -> Vec<Step<String>> {
+        let local_dir = TempDir::new();
+        let remote_dir = TempDir::new();
+        local_dir.write("a.txt", "original");
+        let mut local = local_dir.scan();
+        let base = local.clone_as(local.replica());
+        let mut remote = local.clone_as(ReplicaId::generate());
+
+        local_dir.write("c.txt", "original");
+        rescan(&mut local, local_dir.path());
+        if remote_changes_source {
+            remote_dir.write("a.txt", "remote edit");
+            let path = segments("a.txt");
+            let entry = remote_dir.entry("a.txt").with_path(local.local_path(&path));
+            remote.insert(&path, entry);
+        }
+
+        let merge = base.merge_plan(&local, &remote);
+        plan(&local, &merge.push, &merge.remote_paths())
+    }
+
+    #[test]
+    fn copies_unchanged_source_on_the_receiver() {
+        let steps = push_copy(false);
+        assert!(matches!(
+            steps.as_slice(),
+            [Step::Send(Operation::Copy { from, to, .. })] if *from == segments("a.txt") && *to == segments("c.txt")
+        ));
+    }
+
+    #[test]
+    fn transfers_copy_whose_source_the_receiver_changed() {
+        let steps = push_copy(true);
+        assert!(matches!(
+            steps.as_slice(),
+            [Step::Transfer { from, to, .. }] if *from == segments("c.txt") && *to == segments("c.txt")
+        ));
+    }
+}
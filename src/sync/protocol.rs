@@ -0,0 +1,174 @@
+use std::{hash::Hash, io};
+
+use blake3::Hash as BHash;
+use serde::{Deserialize, Serialize};
+
+use super::delta::{Delta, Signature};
+use crate::{
+    datastructures::{
+        merkle_tree::MerkleTree,
+        version_vector::{ReplicaId, VersionVector},
+    },
+    filesystem::data::MerkleEntry,
+};
+
+/// Largest part of the content of a file that is sent in one message.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// A request of the reconciliation protocol. Paths are the segments relative to the sync root.
+///
+/// Requests are batched per level of the tree, so finding the differences takes one round trip per level.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request<K> {
+    /// The replica and the hash of the root.
+    Root,
+    /// The children of each of the directories.
+    Children(Vec<Vec<K>>),
+    /// The entry and version of each of the files.
+    Files(Vec<Vec<K>>),
+    /// Up to [CHUNK_SIZE] bytes of the content of the file, starting at `offset`. Less means the file ends there.
+    Read { path: Vec<K>, offset: u64 },
+    /// The [Signature] of the file, to send a delta for it.
+    Signature(Vec<K>),
+    /// Keeps the [Signature] the following [Delta](Self::Delta) requests of the connection are answered against.
+    DeltaBase(Signature),
+    /// The part of the delta for the content of the file that starts at `offset`, see [BlockMatcher::delta](super::delta::BlockMatcher::delta).
+    /// An empty part means the file ends there.
+    Delta { path: Vec<K>, offset: u64 },
+    /// Appends `chunk` to the content staged for a file with `hash`, which has `offset` bytes so far.
+    /// The content of a [WriteFile](Operation::WriteFile) is staged in chunks before the operation is sent.
+    Stage {
+        hash: BHash,
+        offset: u64,
+        chunk: Chunk<K>,
+    },
+    /// Changes the directory of the remote, which only replicas that accept changes do.
+    Apply(Operation<K>),
+    /// The sender has the same tree with the root `hash` as the receiver now.
+    /// Both remember it as the state of their last sync with each other.
+    Converged { replica: ReplicaId, hash: BHash },
+}
+
+/// A change to the directory of a replica, e.g. to bring it to the state of the sender.
+///
+/// Parents of a path have to exist, an existing entry at a path that is created is replaced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Operation<K> {
+    /// Removes the entry and everything below it.
+    Remove(Vec<K>),
+    CreateDirectory(Vec<K>),
+    /// Writes a file with the content staged for `hash`, see [Request::Stage].
+    /// The write fails if the staged content does not have the expected `hash`.
+    WriteFile {
+        path: Vec<K>,
+        hash: BHash,
+        version: VersionVector,
+    },
+    /// Copies an entry the receiver already has, so its content does not have to be sent.
+    /// The copy fails if the entry does not have the expected `hash`.
+    Copy {
+        from: Vec<K>,
+        to: Vec<K>,
+        hash: BHash,
+    },
+    /// Renames an entry the receiver already has.
+    Move {
+        from: Vec<K>,
+        to: Vec<K>,
+    },
+    /// Records that the file includes the modifications of `version`, e.g. after a conflict was resolved in its favor.
+    MergeVersion {
+        path: Vec<K>,
+        version: VersionVector,
+    },
+}
+
+/// A part of the content of a file, see [Request::Stage].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Chunk<K> {
+    Data(Vec<u8>),
+    /// The part is built with `delta` from the file that is at `base`.
+    Delta {
+        base: Vec<K>,
+        delta: Delta,
+    },
+}
+
+/// The answer to a [Request] with one item per requested path, in the same order.
+/// A path that is not a directory (for [Request::Children]) or not a file (for [Request::Files]) is answered with `None`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response<K> {
+    Root {
+        replica: ReplicaId,
+        hash: BHash,
+    },
+    Children(Vec<Option<Vec<ChildSummary<K>>>>),
+    Files(Vec<Option<(MerkleEntry, VersionVector)>>),
+    Content(Vec<u8>),
+    Signature(Signature),
+    Delta(Delta),
+    Applied,
+    /// The request could not be answered, e.g. because an operation failed.
+    Failed(String),
+}
+
+/// The error for a response that does not answer the request, e.g. of a remote that runs another version.
+pub(super) fn unexpected_response() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unexpected response from remote",
+    )
+}
+
+/// What is needed about a child to decide whether to descend into it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChildSummary<K> {
+    pub segment: K,
+    pub hash: BHash,
+    pub is_directory: bool,
+}
+
+impl<K: Eq + Ord + Clone + Hash + AsRef<[u8]>> MerkleTree<K> {
+    /// Answers a request of a replica that reconciles with this tree.
+    /// Reading files and operations are refused, they need the directory of the tree.
+    pub fn answer(&self, request: &Request<K>) -> Response<K> {
+        match request {
+            Request::Root => Response::Root {
+                replica: self.replica(),
+                hash: *self.get_hash(&[]),
+            },
+            Request::Children(paths) => Response::Children(
+                paths
+                    .iter()
+                    .map(|path| {
+                        let children = self.children(path)?;
+                        Some(
+                            children
+                                .map(|(segment, hash, data)| ChildSummary {
+                                    segment: segment.clone(),
+                                    hash: *hash,
+                                    is_directory: matches!(data, MerkleEntry::Directory(_)),
+                                })
+                                .collect(),
+                        )
+                    })
+                    .collect(),
+            ),
+            Request::Files(paths) => Response::Files(
+                paths
+                    .iter()
+                    .map(|path| Some((self.try_get(path)?.clone(), self.version(path)?.clone())))
+                    .collect(),
+            ),
+            Request::Read { .. }
+            | Request::Signature(_)
+            | Request::DeltaBase(_)
+            | Request::Delta { .. }
+            | Request::Stage { .. }
+            | Request::Apply(_)
+            | Request::Converged { .. } => {
+                Response::Failed("replica does not serve its directory".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::BTreeMap, hash::Hash, io};
+
+use super::{
+    protocol::{unexpected_response, ChildSummary, Request, Response},
+    transport::Transport,
+};
+use crate::{datastructures::merkle_tree::MerkleTree, filesystem::data::MerkleEntry};
+
+/// Largest number of paths in one request, so messages stay small in huge trees.
+const BATCH_SIZE: usize = 4096;
+
+/// Finds out how the tree of a remote replica differs from `local` without transferring the whole tree.
+///
+/// Starting at the root, the hashes of the children are requested level by level, but only for directories
+/// whose hash differs from the local one, which is the same descent [MerkleTree::find_difference] does locally.
+/// Finally the entries of the differing files are requested. Requests with many paths are split into batches. The transferred data grows with the number of
+/// differences and the size of the directories they are in, not with the size of the tree.
+///
+/// Returns the remote tree: a copy of `local` with every difference replaced by the remote state.
+/// Diff it with `local` (or merge it, see [MerkleTree::merge_plan]) to get the changes themselves.
+/// Entries received from the remote are moved below the local root.
+///
+/// Fails if the remote answers inconsistently, e.g. because it changed during the reconciliation.
+pub fn reconcile<K, T>(local: &MerkleTree<K>, transport: &mut T) -> io::Result<MerkleTree<K>>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+    T: Transport<K>,
+{
+    let (replica, hash) = match transport.request(Request::Root)? {
+        Response::Root { replica, hash } => (replica, hash),
+        Response::Failed(message) => return Err(io::Error::other(message)),
+        _ => return Err(unexpected_response()),
+    };
+    let mut remote = local.clone_as(replica);
+    if hash == *local.get_hash(&[]) {
+        return Ok(remote);
+    }
+
+    let mut level = vec![Vec::new()];
+    let mut files = Vec::new();
+    while !level.is_empty() {
+        let listings =
+            request_batched(
+                transport,
+                &level,
+                Request::Children,
+                |response| match response {
+                    Response::Children(listings) => Some(listings),
+                    _ => None,
+                },
+            )?;
+
+        let mut next_level = Vec::new();
+        for (path, listing) in level.into_iter().zip(listings) {
+            let listing = listing.ok_or_else(remote_changed)?;
+            let mut local_children = local
+                .children(&path)
+                .into_iter()
+                .flatten()
+                .map(|(segment, hash, data)| (segment, (hash, data)))
+                .collect::<BTreeMap<_, _>>();
+
+            for ChildSummary {
+                segment,
+                hash,
+                is_directory,
+            } in listing
+            {
+                let local_child = local_children.remove(&segment);
+                let child = child_path(&path, segment);
+                let local_is_directory = match local_child {
+                    Some((local_hash, _)) if *local_hash == hash => continue,
+                    Some((_, data)) => matches!(data, MerkleEntry::Directory(_)),
+                    None => false,
+                };
+                if !is_directory {
+                    files.push(child);
+                    continue;
+                }
+                if !local_is_directory {
+                    remote.try_remove(&child);
+                    let directory = MerkleEntry::placeholder_directory(&local.local_path(&child));
+                    remote
+                        .try_insert(&child, directory)
+                        .map_err(|_| remote_changed())?;
+                }
+                next_level.push(child);
+            }
+
+            // what is left only exists locally
+            for segment in local_children.into_keys() {
+                remote.try_remove(&child_path(&path, segment.clone()));
+            }
+        }
+        level = next_level;
+    }
+
+    if !files.is_empty() {
+        let entries = request_batched(
+            transport,
+            &files,
+            Request::Files,
+            |response| match response {
+                Response::Files(entries) => Some(entries),
+                _ => None,
+            },
+        )?;
+        for (path, entry) in files.into_iter().zip(entries) {
+            let (entry, version) = entry.ok_or_else(remote_changed)?;
+            // the version of a file that is replaced must not carry over
+            remote.try_remove(&path);
+            remote
+                .insert_synced(&path, entry.with_path(local.local_path(&path)), &version)
+                .map_err(|_| remote_changed())?;
+        }
+    }
+
+    if *remote.get_hash(&[]) != hash {
+        return Err(remote_changed());
+    }
+    Ok(remote)
+}
+
+/// Sends a request for `paths` in batches of at most [BATCH_SIZE] paths and collects the answers, one per path.
+fn request_batched<K, T, A>(
+    transport: &mut T,
+    paths: &[Vec<K>],
+    request: fn(Vec<Vec<K>>) -> Request<K>,
+    answers: fn(Response<K>) -> Option<Vec<A>>,
+) -> io::Result<Vec<A>>
+where
+    K: Clone,
+    T: Transport<K>,
+{
+    let mut all = Vec::with_capacity(paths.len());
+    for batch in paths.chunks(BATCH_SIZE) {
+        match answers(transport.request(request(batch.to_vec()))?) {
+            Some(batch_answers) if batch_answers.len() == batch.len() => all.extend(batch_answers),
+            _ => return Err(unexpected_response()),
+        }
+    }
+    Ok(all)
+}
+
+fn child_path<K: Clone>(path: &[K], segment: K) -> Vec<K> {
+    let mut path = path.to_vec();
+    path.push(segment);
+    path
+}
+
+fn remote_changed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Interrupted,
+        "remote changed during reconciliation",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::reconcile;
+    use crate::{
+        datastructures::{merkle_tree::MerkleTree, version_vector::ReplicaId},
+        filesystem::data::MerkleEntry,
+        sync::transport::MemoryTransport,
+        test_util::segments,
+    };
+
+    /// A tree with `dirs` × `dirs` directories of ten files each, which only exists in memory.
+    fn tree(dirs: usize) -> MerkleTree<String> {
+        let root = MerkleEntry::placeholder_directory(Path::new("/replica"));
+        let mut tree = MerkleTree::new("/replica".to_string(), root);
+        for i in 0..dirs {
+            for j in 0..dirs {
+                for k in 0..10 {
+                    write(&mut tree, &format!("{i}/{j}/{k}"), "content");
+                }
+            }
+        }
+        tree
+    }
+
+    fn write(tree: &mut MerkleTree<String>, path: &str, content: &str) {
+        let path = segments(path);
+        let hash = blake3::hash(content.as_bytes());
+        let entry = MerkleEntry::pending_file(tree.local_path(&path), hash, content.len() as u64);
+        tree.insert(&path, entry);
+    }
+
+    /// Bytes transferred to reconcile with a remote that changed the first `changes` files of a tree of `dirs` × `dirs` directories.
+    fn transferred(dirs: usize, changes: usize) -> usize {
+        let local = tree(dirs);
+        let mut remote = local.clone_as(ReplicaId::generate());
+        for i in 0..changes {
+            write(&mut remote, &format!("{i}/{i}/0"), "changed");
+        }
+        let mut transport = MemoryTransport::new(&remote);
+        reconcile(&local, &mut transport).unwrap();
+        transport.transferred
+    }
+
+    #[test]
+    fn finds_what_the_remote_changed() {
+        let local = tree(3);
+        let mut remote = local.clone_as(ReplicaId::generate());
+        write(&mut remote, "0/1/2", "changed");
+        write(&mut remote, "1/1/new", "new");
+        write(&mut remote, "2/new/file", "in a new directory");
+        remote.remove(&segments("2/0/3"));
+        remote.remove(&segments("1/2"));
+
+        let reconciled = reconcile(&local, &mut MemoryTransport::new(&remote)).unwrap();
+        assert_eq!(reconciled.get_hash(&[]), remote.get_hash(&[]));
+        let mut changed = local
+            .find_difference(&reconciled)
+            .iter()
+            .map(|change| change.path().join("/"))
+            .collect::<Vec<_>>();
+        changed.sort();
+        let removed_dir = (0..10).map(|k| format!("1/2/{k}"));
+        let mut expected = ["0/1/2", "1/1/new", "1/2", "2/0/3", "2/new", "2/new/file"]
+            .map(str::to_string)
+            .into_iter()
+            .chain(removed_dir)
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(changed, expected);
+    }
+
+    #[test]
+    fn transfer_grows_with_the_changes_not_the_tree() {
+        let small = transferred(4, 1);
+        // 36 times as many files
+        let large = transferred(24, 1);
+        assert!(
+            large < 4 * small,
+            "{large} bytes for one change, {small} in a small tree"
+        );
+        let many = transferred(24, 10);
+        assert!(
+            many > 5 * large,
+            "{many} bytes for ten changes, {large} for one"
+        );
+        assert_eq!(transferred(24, 0), transferred(4, 0));
+    }
+}
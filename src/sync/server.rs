@@ -0,0 +1,123 @@
+use std::{
+    fs::File,
+    hash::Hash,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    apply::{apply_operation, checked_path, stage},
+    delta::{map_file, BlockMatcher, Signature},
+    protocol::{Request, Response, CHUNK_SIZE},
+    session::save_base,
+};
+use crate::{
+    datastructures::merkle_tree::MerkleTree, error::SyncronError, filesystem::data::MerkleEntry,
+};
+
+/// What is kept between the requests of one connection.
+#[derive(Default)]
+pub struct Connection {
+    /// made of the signature of the last [Request::DeltaBase]
+    matcher: Option<BlockMatcher>,
+}
+
+/// Answers a request of a replica that syncs with `tree` over `connection`.
+/// Operations are applied to the directory of `tree` and then to `tree` itself.
+/// The state of converged syncs is kept in `bases`, see [sync](super::session::sync).
+pub fn handle_request<K>(
+    tree: &mut MerkleTree<K>,
+    bases: &Path,
+    connection: &mut Connection,
+    request: Request<K>,
+) -> Response<K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + Serialize + DeserializeOwned,
+{
+    match request {
+        Request::Apply(operation) => match apply_operation(tree, operation) {
+            Ok(()) => Response::Applied,
+            Err(err) => Response::Failed(err.to_string()),
+        },
+        Request::Read { path, offset } => match read_chunk(tree, &path, offset) {
+            Ok(content) => Response::Content(content),
+            Err(err) => Response::Failed(err.to_string()),
+        },
+        Request::Stage {
+            hash,
+            offset,
+            chunk,
+        } => match stage(tree, &hash, offset, &chunk) {
+            Ok(_) => Response::Applied,
+            Err(err) => Response::Failed(err.to_string()),
+        },
+        Request::Signature(path) => match map_tree_file(tree, &path) {
+            Ok(content) => Response::Signature(Signature::new(&content)),
+            Err(err) => Response::Failed(err.to_string()),
+        },
+        Request::DeltaBase(signature) => {
+            connection.matcher = Some(BlockMatcher::new(signature));
+            Response::Applied
+        }
+        Request::Delta { path, offset } => {
+            let Some(matcher) = &connection.matcher else {
+                return Response::Failed("no signature to make a delta against".to_string());
+            };
+            match map_tree_file(tree, &path) {
+                Ok(content) => {
+                    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+                    Response::Delta(matcher.delta(&content, offset).0)
+                }
+                Err(err) => Response::Failed(err.to_string()),
+            }
+        }
+        Request::Converged { replica, hash } if hash == *tree.get_hash(&[]) => {
+            match save_base(tree, bases, replica) {
+                Ok(()) => Response::Applied,
+                Err(err) => Response::Failed(format!("unable to save sync state: {err}")),
+            }
+        }
+        Request::Converged { .. } => Response::Failed("replicas are not in sync".to_string()),
+        request => tree.answer(&request),
+    }
+}
+
+/// The path on disk of the file at `path` of `tree`.
+fn file_path<K>(tree: &MerkleTree<K>, path: &[K]) -> Result<PathBuf, SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let local = checked_path(tree, path)?;
+    match tree.try_get(path) {
+        Some(MerkleEntry::File(_)) => Ok(local),
+        _ => Err(SyncronError::Vanished(local)),
+    }
+}
+
+/// Maps the file at `path` of `tree` into memory.
+fn map_tree_file<K>(tree: &MerkleTree<K>, path: &[K]) -> Result<Mmap, SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let local = file_path(tree, path)?;
+    map_file(&local).map_err(|err| SyncronError::from_io(local, err))
+}
+
+/// Reads up to [CHUNK_SIZE] bytes of the file at `path` of `tree`, starting at `offset`.
+fn read_chunk<K>(tree: &MerkleTree<K>, path: &[K], offset: u64) -> Result<Vec<u8>, SyncronError>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let local = file_path(tree, path)?;
+    let read = || {
+        let mut file = File::open(&local)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut chunk = Vec::new();
+        file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    };
+    read().map_err(|err| SyncronError::from_io(local.clone(), err))
+}
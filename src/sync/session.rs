@@ -0,0 +1,467 @@
+use std::{
+    fs::File,
+    hash::Hash,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use blake3::Hash as BHash;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    apply::apply_operation,
+    delta::{map_file, BlockMatcher, Signature, MIN_DELTA_SIZE},
+    plan::{plan, plan_resolution, Step},
+    protocol::{unexpected_response, Chunk, Operation, Request, Response, CHUNK_SIZE},
+    reconcile::reconcile,
+    transport::Transport,
+};
+use crate::{
+    datastructures::{
+        conflict::ConflictResolver,
+        merkle_tree::MerkleTree,
+        version_vector::{ReplicaId, VersionVector},
+    },
+    error::SyncronError,
+    filesystem::{applier::stage_chunk, data::MerkleEntry},
+};
+
+/// Result of [sync].
+#[derive(Debug)]
+pub struct SyncReport {
+    /// number of operations the remote applied
+    pub pushed: usize,
+    /// number of operations applied locally
+    pub pulled: usize,
+    /// paths both sides changed, resolved with the [ConflictResolver]
+    pub conflicts: usize,
+    /// operations that failed locally or on the remote
+    pub errors: Vec<SyncronError>,
+    /// whether both replicas have the same root hash afterwards
+    pub converged: bool,
+}
+
+/// Syncs `local` with the remote replica in both directions.
+///
+/// Both trees are compared against the state they had after their last sync, which both sides keep per replica
+/// in their `bases`. What only one side changed is copied to the other side, deletes included. Paths both sides changed
+/// are resolved with `resolver`. Without a previous sync, everything counts as added on both sides.
+///
+/// Operations that fail are reported and skipped. The state of the sync is only kept once both replicas
+/// converged, so the next sync picks up what is left.
+pub fn sync<K, T>(
+    local: &mut MerkleTree<K>,
+    bases: &Path,
+    resolver: &ConflictResolver,
+    transport: &mut T,
+) -> io::Result<SyncReport>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + for<'a> From<&'a str> + Serialize + DeserializeOwned,
+    T: Transport<K>,
+{
+    let remote = reconcile(local, transport)?;
+    let base_path = base_path(bases, remote.replica());
+    let base =
+        MerkleTree::load(&base_path, &local.local_path(&[])).unwrap_or_else(|_| empty_base(local));
+
+    let (remote_steps, local_steps, conflicts) = {
+        let merge = base.merge_plan(local, &remote);
+        let mut remote_steps = plan(local, &merge.push, &merge.remote_paths());
+        let mut local_steps = plan(&remote, &merge.pull, &merge.local_paths());
+        for conflict in &merge.conflicts {
+            for resolution in resolver.resolve(local, &remote, conflict) {
+                let (remote_resolution, local_resolution) =
+                    plan_resolution(local, &remote, &resolution);
+                remote_steps.extend(remote_resolution);
+                local_steps.extend(local_resolution);
+            }
+        }
+        (remote_steps, local_steps, merge.conflicts.len())
+    };
+
+    let mut report = SyncReport {
+        pushed: 0,
+        pulled: 0,
+        conflicts,
+        errors: Vec::new(),
+        converged: false,
+    };
+    // the remote reads files of the local replica as they were planned, so it goes first
+    for step in remote_steps {
+        let result = match step {
+            Step::Send(operation) => applied(transport.request(Request::Apply(operation))?)?,
+            Step::Transfer {
+                from,
+                to,
+                hash,
+                version,
+            } => push_file(transport, local, &remote, &from, to, hash, version)?,
+        };
+        match result {
+            Ok(()) => report.pushed += 1,
+            Err(err) => report.errors.push(err),
+        }
+    }
+    for step in local_steps {
+        let result = match step {
+            Step::Send(operation) => apply_operation(local, operation),
+            Step::Transfer {
+                from,
+                to,
+                hash,
+                version,
+            } => pull_file(transport, local, from, &to, hash)?.and_then(|()| {
+                let operation = Operation::WriteFile {
+                    path: to,
+                    hash,
+                    version,
+                };
+                apply_operation(local, operation)
+            }),
+        };
+        match result {
+            Ok(()) => report.pulled += 1,
+            Err(err) => report.errors.push(err),
+        }
+    }
+
+    let Response::Root { hash, .. } = transport.request(Request::Root)? else {
+        return Err(unexpected_response());
+    };
+    report.converged = hash == *local.get_hash(&[]);
+    if report.converged {
+        converged(local, bases, remote.replica(), transport)?;
+    }
+    Ok(report)
+}
+
+/// Writes the local file at `from` to the file at `to` of the remote. If the remote has a large file there, only a delta
+/// against it is sent. The whole file is still sent if the delta can't be applied, e.g. because the file changed since.
+///
+/// Fails if the connection fails. The inner result tells whether the file was written.
+fn push_file<K, T>(
+    transport: &mut T,
+    local: &MerkleTree<K>,
+    remote: &MerkleTree<K>,
+    from: &[K],
+    to: Vec<K>,
+    hash: BHash,
+    version: VersionVector,
+) -> io::Result<Result<(), SyncronError>>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+    T: Transport<K>,
+{
+    let file = local.local_path(from);
+    if has_delta_base(remote, &to) {
+        if let Response::Signature(signature) = transport.request(Request::Signature(to.clone()))? {
+            let content = match map_file(&file) {
+                Ok(content) => content,
+                Err(err) => return Ok(Err(SyncronError::from_io(file, err))),
+            };
+            let matcher = BlockMatcher::new(signature);
+            if upload_delta(transport, &content, &matcher, &to, hash)?.is_ok() {
+                let operation = Operation::WriteFile {
+                    path: to.clone(),
+                    hash,
+                    version: version.clone(),
+                };
+                match transport.request(Request::Apply(operation))? {
+                    Response::Failed(_) => {}
+                    response => return applied(response),
+                }
+            }
+        }
+    }
+    if let Err(err) = upload(transport, &file, hash)? {
+        return Ok(Err(err));
+    }
+    let operation = Operation::WriteFile {
+        path: to,
+        hash,
+        version,
+    };
+    applied(transport.request(Request::Apply(operation))?)
+}
+
+/// Stages the content of the local `file` with `hash` on the remote in chunks, see [Request::Stage].
+fn upload<K, T>(transport: &mut T, file: &Path, hash: BHash) -> io::Result<Result<(), SyncronError>>
+where
+    T: Transport<K>,
+{
+    let mut reader = match File::open(file) {
+        Ok(reader) => reader,
+        Err(err) => return Ok(Err(SyncronError::from_io(file.to_owned(), err))),
+    };
+    let mut offset = 0;
+    loop {
+        let mut data = Vec::new();
+        if let Err(err) = (&mut reader).take(CHUNK_SIZE as u64).read_to_end(&mut data) {
+            return Ok(Err(SyncronError::from_io(file.to_owned(), err)));
+        }
+        let len = data.len();
+        let request = Request::Stage {
+            hash,
+            offset,
+            chunk: Chunk::Data(data),
+        };
+        if let Err(err) = applied(transport.request(request)?)? {
+            return Ok(Err(err));
+        }
+        offset += len as u64;
+        if len < CHUNK_SIZE {
+            return Ok(Ok(()));
+        }
+    }
+}
+
+/// Stages `content` with `hash` on the remote as a delta against its file at `base`, made with `matcher`.
+fn upload_delta<K, T>(
+    transport: &mut T,
+    content: &[u8],
+    matcher: &BlockMatcher,
+    base: &[K],
+    hash: BHash,
+) -> io::Result<Result<(), SyncronError>>
+where
+    K: Clone,
+    T: Transport<K>,
+{
+    let mut offset = 0;
+    loop {
+        let (delta, end) = matcher.delta(content, offset);
+        let request = Request::Stage {
+            hash,
+            offset: offset as u64,
+            chunk: Chunk::Delta {
+                base: base.to_vec(),
+                delta,
+            },
+        };
+        if let Err(err) = applied(transport.request(request)?)? {
+            return Ok(Err(err));
+        }
+        offset = end;
+        if offset == content.len() {
+            return Ok(Ok(()));
+        }
+    }
+}
+
+/// Stages the content of the file at `from` of the remote with `hash` locally, so it can be written to `to`.
+/// If there is a large local file at `to`, which it replaces, only a delta against it is transferred.
+///
+/// Fails if the connection fails. The inner result tells whether the content was staged.
+fn pull_file<K, T>(
+    transport: &mut T,
+    local: &MerkleTree<K>,
+    from: Vec<K>,
+    to: &[K],
+    hash: BHash,
+) -> io::Result<Result<(), SyncronError>>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+    T: Transport<K>,
+{
+    let root = local.local_path(&[]);
+    if has_delta_base(local, to) {
+        if let Ok(base) = map_file(&local.local_path(to)) {
+            match transport.request(Request::DeltaBase(Signature::new(&base)))? {
+                Response::Applied => {}
+                Response::Failed(message) => return Ok(Err(SyncronError::Remote(message))),
+                _ => return Err(unexpected_response()),
+            }
+            let mut offset = 0;
+            loop {
+                let request = Request::Delta {
+                    path: from.clone(),
+                    offset,
+                };
+                let delta = match transport.request(request)? {
+                    Response::Delta(delta) => delta,
+                    Response::Failed(message) => return Ok(Err(SyncronError::Remote(message))),
+                    _ => return Err(unexpected_response()),
+                };
+                match stage_chunk(&root, &hash, offset, |file| delta.apply(&base, file)) {
+                    Ok(_) if delta.is_empty() => return Ok(Ok(())),
+                    Ok(staged) => offset = staged,
+                    // the local file changed since its signature was made
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+    let mut offset = 0;
+    loop {
+        let request = Request::Read {
+            path: from.clone(),
+            offset,
+        };
+        let data = match transport.request(request)? {
+            Response::Content(data) => data,
+            Response::Failed(message) => return Ok(Err(SyncronError::Remote(message))),
+            _ => return Err(unexpected_response()),
+        };
+        if let Err(err) = stage_chunk(&root, &hash, offset, |file| file.write_all(&data)) {
+            return Ok(Err(err));
+        }
+        offset += data.len() as u64;
+        if data.len() < CHUNK_SIZE {
+            return Ok(Ok(()));
+        }
+    }
+}
+
+/// Whether the remote did what it was asked to. Fails if the response is no answer to that.
+fn applied<K>(response: Response<K>) -> io::Result<Result<(), SyncronError>> {
+    match response {
+        Response::Applied => Ok(Ok(())),
+        Response::Failed(message) => Ok(Err(SyncronError::Remote(message))),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Whether `tree` has a file at `path` that is large enough to send a delta against it.
+fn has_delta_base<K>(tree: &MerkleTree<K>, path: &[K]) -> bool
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    matches!(tree.try_get(path), Some(entry @ MerkleEntry::File(_)) if entry.get_size() >= MIN_DELTA_SIZE)
+}
+
+/// Remembers on both sides that `local` and the remote `replica` have the same tree now.
+pub fn converged<K, T>(
+    local: &MerkleTree<K>,
+    bases: &Path,
+    replica: ReplicaId,
+    transport: &mut T,
+) -> io::Result<()>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + Serialize + DeserializeOwned,
+    T: Transport<K>,
+{
+    save_base(local, bases, replica)?;
+    let request = Request::Converged {
+        replica: local.replica(),
+        hash: *local.get_hash(&[]),
+    };
+    match transport.request(request)? {
+        Response::Applied => Ok(()),
+        Response::Failed(message) => Err(io::Error::other(message)),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Keeps `tree` as the state of the last sync with `replica`.
+pub fn save_base<K>(tree: &MerkleTree<K>, bases: &Path, replica: ReplicaId) -> io::Result<()>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + Serialize + DeserializeOwned,
+{
+    tree.save(&base_path(bases, replica))
+}
+
+/// Where the state after the last sync with `replica` is kept.
+fn base_path(bases: &Path, replica: ReplicaId) -> PathBuf {
+    bases.join(replica.to_string())
+}
+
+/// The state before the first sync, where neither side has anything.
+fn empty_base<K>(local: &MerkleTree<K>) -> MerkleTree<K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]>,
+{
+    let mut base = local.clone_as(local.replica());
+    let children = base
+        .children(&[])
+        .into_iter()
+        .flatten()
+        .map(|(segment, _, _)| vec![segment.clone()])
+        .collect::<Vec<_>>();
+    for child in children {
+        base.try_remove(&child);
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::TcpListener, path::PathBuf, thread};
+
+    use super::{sync, SyncReport};
+    use crate::{
+        datastructures::{
+            conflict::{ConflictResolver, ConflictStrategy},
+            merkle_tree::MerkleTree,
+        },
+        filesystem::SYNCRON_DIR,
+        sync::{
+            protocol::CHUNK_SIZE,
+            server::{handle_request, Connection},
+            transport::{serve_connection, TcpTransport},
+        },
+        test_util::{rescan, TempDir},
+    };
+
+    fn bases(dir: &TempDir) -> PathBuf {
+        dir.path().join(SYNCRON_DIR).join("peers")
+    }
+
+    /// Syncs `client` with `server`, which serves its tree on localhost for one connection.
+    fn sync_over_tcp(
+        client: (&TempDir, &mut MerkleTree<String>),
+        server: (&TempDir, &mut MerkleTree<String>),
+    ) -> SyncReport {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (server_dir, server_tree) = server;
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                let bases = bases(server_dir);
+                let mut connection = Connection::default();
+                serve_connection(stream, |request| {
+                    handle_request(server_tree, &bases, &mut connection, request)
+                })
+                .unwrap();
+            });
+            let mut transport = TcpTransport::connect(addr).unwrap();
+            let resolver = ConflictResolver::new(ConflictStrategy::KeepBoth);
+            sync(client.1, &bases(client.0), &resolver, &mut transport).unwrap()
+        })
+    }
+
+    #[test]
+    fn syncs_two_directories_over_tcp() {
+        let (client, server) = (TempDir::new(), TempDir::new());
+        // spans several chunks
+        let big = (0..3 * CHUNK_SIZE + 5)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        client.write("big", &big);
+        client.write("dir/a.txt", "from the client");
+        server.write("s.txt", "from the server");
+        let (mut client_tree, mut server_tree) = (client.scan(), server.scan());
+
+        let report = sync_over_tcp((&client, &mut client_tree), (&server, &mut server_tree));
+        assert!(report.converged && report.errors.is_empty(), "{report:?}");
+        assert_eq!(fs::read(server.path().join("big")).unwrap(), big);
+        assert_eq!(
+            fs::read_to_string(server.path().join("dir/a.txt")).unwrap(),
+            "from the client"
+        );
+        assert_eq!(
+            fs::read_to_string(client.path().join("s.txt")).unwrap(),
+            "from the server"
+        );
+
+        // sent as a delta against the version the client has
+        let mut edited = big.clone();
+        edited.splice(1000..1000, *b"inserted");
+        server.write("big", &edited);
+        rescan(&mut server_tree, server.path());
+        let report = sync_over_tcp((&client, &mut client_tree), (&server, &mut server_tree));
+        assert!(report.converged && report.pulled == 1, "{report:?}");
+        assert_eq!(fs::read(client.path().join("big")).unwrap(), edited);
+        assert_eq!(client_tree.get_hash(&[]), server_tree.get_hash(&[]));
+    }
+}
@@ -0,0 +1,117 @@
+#[cfg(test)]
+use std::hash::Hash;
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::protocol::{Request, Response};
+#[cfg(test)]
+use crate::datastructures::merkle_tree::MerkleTree;
+
+/// Sends requests of the reconciliation protocol to a remote replica and waits for its response.
+pub trait Transport<K> {
+    fn request(&mut self, request: Request<K>) -> io::Result<Response<K>>;
+}
+
+/// Answers requests with a tree in the same process. Messages are still encoded as they would be
+/// on the wire, so the number of transferred bytes can be observed.
+#[cfg(test)]
+pub struct MemoryTransport<'a, K: AsRef<[u8]>> {
+    remote: &'a MerkleTree<K>,
+    /// encoded size of all requests and responses so far
+    pub transferred: usize,
+}
+#[cfg(test)]
+impl<'a, K: AsRef<[u8]>> MemoryTransport<'a, K> {
+    pub fn new(remote: &'a MerkleTree<K>) -> Self {
+        Self {
+            remote,
+            transferred: 0,
+        }
+    }
+}
+#[cfg(test)]
+impl<K> Transport<K> for MemoryTransport<'_, K>
+where
+    K: Eq + Ord + Clone + Hash + AsRef<[u8]> + Serialize + DeserializeOwned,
+{
+    fn request(&mut self, request: Request<K>) -> io::Result<Response<K>> {
+        let request: Request<K> = self.transfer(&request)?;
+        let response = self.remote.answer(&request);
+        self.transfer(&response)
+    }
+}
+#[cfg(test)]
+impl<K: AsRef<[u8]>> MemoryTransport<'_, K> {
+    /// Encodes and decodes `message` like sending it to the other side would.
+    fn transfer<T: Serialize + DeserializeOwned>(&mut self, message: &T) -> io::Result<T> {
+        let options = bincode::DefaultOptions::new();
+        let bytes = options.serialize(message).map_err(invalid_data)?;
+        self.transferred += bytes.len();
+        options.deserialize(&bytes).map_err(invalid_data)
+    }
+}
+
+/// Upper bound for the size of a single message. Files are sent in chunks of [CHUNK_SIZE](super::protocol::CHUNK_SIZE),
+/// requests for many paths are split into batches. A directory with millions of entries is still listed in one message.
+const MAX_MESSAGE_SIZE: u64 = 256 << 20;
+
+/// Sends requests to a replica that serves its tree over TCP, see [serve_connection].
+pub struct TcpTransport {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+impl TcpTransport {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+}
+impl<K: Serialize + DeserializeOwned> Transport<K> for TcpTransport {
+    fn request(&mut self, request: Request<K>) -> io::Result<Response<K>> {
+        send(&mut self.writer, &request)?;
+        receive(&mut self.reader)
+    }
+}
+
+/// Answers the requests of a replica connected via `stream` with `handle` until it disconnects.
+pub fn serve_connection<K: Serialize + DeserializeOwned>(
+    stream: TcpStream,
+    mut handle: impl FnMut(Request<K>) -> Response<K>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    // a disconnect between requests ends the session, within a request it is an error
+    while !reader.fill_buf()?.is_empty() {
+        let request = receive(&mut reader)?;
+        send(&mut writer, &handle(request))?;
+    }
+    Ok(())
+}
+
+fn send<T: Serialize>(writer: &mut BufWriter<TcpStream>, message: &T) -> io::Result<()> {
+    let options = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+    options
+        .serialize_into(&mut *writer, message)
+        .map_err(invalid_data)?;
+    writer.flush()
+}
+
+fn receive<T: DeserializeOwned>(reader: &mut BufReader<TcpStream>) -> io::Result<T> {
+    let options = bincode::DefaultOptions::new().with_limit(MAX_MESSAGE_SIZE);
+    options.deserialize_from(reader).map_err(|err| match *err {
+        bincode::ErrorKind::Io(err) => err,
+        err => invalid_data(Box::new(err)),
+    })
+}
+
+fn invalid_data(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
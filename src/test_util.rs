@@ -0,0 +1,91 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    datastructures::merkle_tree::MerkleTree,
+    filesystem::{
+        data::{MerkleEntry, PreviousScan},
+        scan::{walk_directory, IgnoreOptions},
+    },
+};
+
+/// Ignore files outside of the test directories must not change what tests see.
+pub const OPTIONS: IgnoreOptions = IgnoreOptions { gitignore: false };
+
+/// A directory below the system temp directory that is removed when dropped.
+pub struct TempDir(PathBuf);
+impl TempDir {
+    pub fn new() -> Self {
+        static CREATED: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "syncron-test-{}-{}",
+            process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn root(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// Writes `content` to the file at `relative`, creating its parents.
+    pub fn write(&self, relative: &str, content: impl AsRef<[u8]>) {
+        let path = self.0.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Sets the modification time of the file at `relative` to `secs` after the epoch.
+    pub fn set_modified(&self, relative: &str, secs: u64) {
+        let file = File::options()
+            .write(true)
+            .open(self.0.join(relative))
+            .unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    /// The entry of the file or directory at `relative`, as a scan would read it.
+    pub fn entry(&self, relative: &str) -> MerkleEntry {
+        MerkleEntry::from_path(self.0.join(relative), None).unwrap()
+    }
+
+    /// A tree of a new replica with everything in the directory.
+    pub fn scan(&self) -> MerkleTree<String> {
+        let root = MerkleEntry::from_path(self.0.clone(), None).unwrap();
+        let mut tree = MerkleTree::new(self.root().to_string(), root);
+        rescan(&mut tree, self.path());
+        tree
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Merges a full scan of `root` into `tree` and checks that it succeeded.
+pub fn rescan(tree: &mut MerkleTree<String>, root: &Path) {
+    let previous = PreviousScan::from_entries(tree.entries());
+    let receiver = walk_directory(root.to_owned(), OPTIONS, previous);
+    let report = tree.apply_scan(root, receiver);
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+}
+
+/// Segments of a path relative to a tree root, e.g. `segments("dir/file")`.
+pub fn segments(path: &str) -> Vec<String> {
+    path.split('/').map(str::to_string).collect()
+}